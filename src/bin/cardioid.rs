@@ -19,6 +19,12 @@ fn main() -> Result<(), Box<dyn Error>> {
         .version("0.1.0")
         .author("Matthias Wolf <m@sushinara.net>")
         .about("Generate the cardiod of Mandelbrot fractals")
+        .arg(
+            Arg::with_name("cache")
+                .takes_value(true)
+                .long("cache")
+                .help("A cache file to use; default: value of 'config' with extension 'cache'"),
+        )
         .arg(
             Arg::with_name("config")
                 .takes_value(true)
@@ -37,31 +43,28 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let config_filename = cli.value_of("config").unwrap();
     let config_filestub = Path::new(config_filename).file_stem().unwrap();
-    let config = Configuration::load(config_filename).unwrap();
+    let config = Configuration::load(config_filename)
+        .map_err(|e| format!("cannot load {}: {}", config_filename, e))?;
 
     let cache_filename_default = format!("{}.cache", config_filestub.to_str().unwrap());
-    let cache_filename = cli.value_of("self").unwrap_or(&cache_filename_default);
-    let mut cache = Cache::load(&cache_filename, &config);
+    let cache_filename = cli.value_of("cache").unwrap_or(&cache_filename_default);
+    let mut cache = Cache::load(cache_filename, &config);
 
     if !cache.valid {
         cache.populate();
-        cache.dump(cache_filename).unwrap();
+        cache.dump(cache_filename)?;
     }
 
     let filename = cli.value_of("filename").unwrap();
     let mut imgbuf: image::RgbImage =
         image::ImageBuffer::new(config.dimensions.x as u32, config.dimensions.y as u32);
 
+    let viewport = config.viewport();
     imgbuf
         .enumerate_pixels_mut()
         .par_bridge()
         .for_each(|(x, y, pixel)| {
-            let re = (x as f64 + 0.5) / config.dimensions.x as f64
-                * (config.area.x[1] - config.area.x[0])
-                + config.area.x[0];
-            let im = (y as f64 + 0.5) / config.dimensions.y as f64
-                * (config.area.y[1] - config.area.y[0])
-                + config.area.y[0];
+            let (re, im) = viewport.pixel_center(x as u16, y as u16);
             let c = Complex { re, im };
             if cardioid(c) || first_bulb(c) {
                 *pixel = image::Rgb([0, 0, 0]);
@@ -3,28 +3,325 @@ extern crate clap;
 extern crate log;
 extern crate rostbrot;
 
-use rostbrot::cache::{Cache, Configuration};
+use rostbrot::cache::{Cache, Configuration, Shard};
 use rostbrot::color::colorize;
+use rostbrot::export;
+use rostbrot::format::{self, CacheError, Compression};
+use rostbrot::store::Store;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::error::Error;
+use std::fs;
+use std::ops::Range;
 use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Parse a range of bins given as `start:end`
+fn parse_range(s: &str) -> Result<Range<u16>, String> {
+    let bounds: Vec<_> = s.split(':').map(|b| b.trim().parse::<u16>()).collect();
+    match bounds.as_slice() {
+        [Ok(start), Ok(end)] if start < end => Ok(*start..*end),
+        _ => Err(format!("expected a range 'start:end' of bins, got '{}'", s)),
+    }
+}
+
+fn read_cache(filename: &str) -> Result<Cache, Box<dyn Error>> {
+    Cache::read(filename).map_err(|e| format!("cannot read {}: {}", filename, e).into())
+}
+
+fn compress_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("compress").long("compress").help(
+        "Compress the cache file written with deflate; bins are always stored densely, sparsely, \
+             or delta encoded, whichever is smallest",
+    )
+}
+
+fn compression(args: &ArgMatches) -> Compression {
+    if args.is_present("compress") {
+        Compression::Deflate
+    } else {
+        Compression::None
+    }
+}
+
+fn cache_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(compress_arg())
+        .arg(
+            Arg::with_name("input")
+                .takes_value(true)
+                .required(true)
+                .index(1)
+                .help("A populated cache file"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .takes_value(true)
+                .required(true)
+                .index(2)
+                .help("The cache file to write"),
+        )
+}
+
+/// Write a cache derived from the input cache of `args` by `op`
+fn derive_cache<F>(args: &ArgMatches, op: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&Cache) -> Result<Cache, Box<dyn Error>>,
+{
+    let cache = read_cache(args.value_of("input").unwrap())?;
+    let derived = op(&cache)?;
+    let output = args.value_of("output").unwrap();
+    derived.dump_with(output, compression(args))?;
+    info!(
+        "wrote {} with area {:?} and dimensions {:?}, sampled with {:?}",
+        output,
+        derived.area(),
+        derived.dimensions(),
+        derived.sampling()
+    );
+    Ok(())
+}
+
+fn merge(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mut inputs = args.values_of("inputs").unwrap();
+    let first = inputs.next().unwrap();
+    let mut merged = read_cache(first)?;
+    for input in inputs {
+        merged
+            .merge(&read_cache(input)?)
+            .map_err(|e| format!("cannot merge {} into {}: {}", input, first, e))?;
+    }
+    let output = args.value_of("output").unwrap();
+    merged.dump_with(output, compression(args))?;
+    info!("wrote {} with {} samples", output, merged.samples());
+    Ok(())
+}
+
+/// Parse a number of bytes with an optional binary suffix K, M, G or T
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let n = digits
+        .parse::<u64>()
+        .map_err(|_| format!("expected a size like '500M', got '{}'", s))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| format!("the size '{}' is too large", s))
+}
+
+/// A short description of the time passed since `t`
+fn age(t: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(t)
+        .unwrap_or_default()
+        .as_secs();
+    match secs {
+        0..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn list(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let store = Store::open(args.value_of("cache-dir").unwrap())?;
+    for entry in store.entries()? {
+        println!(
+            "{}  {:>8.1} MiB  {:>4}  {}",
+            entry.id,
+            entry.size as f64 / (1 << 20) as f64,
+            age(entry.used),
+            entry.source.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+fn gc(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let store = Store::open(args.value_of("cache-dir").unwrap())?;
+    let max_age = match args.value_of("older-than") {
+        Some(days) => Some(Duration::from_secs(days.parse::<u64>()? * 86400)),
+        None => None,
+    };
+    let max_size = match args.value_of("max-size") {
+        Some(size) => Some(parse_size(size)?),
+        None => None,
+    };
+    for entry in store.gc(max_age, max_size)? {
+        info!(
+            "removed cache {} of {}",
+            entry.id,
+            entry.source.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+fn layer_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("layer")
+        .takes_value(true)
+        .long("layer")
+        .default_value("0")
+        .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+        .help("The index of the layer")
+}
+
+fn export_layer(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let cache = read_cache(args.value_of("cache").unwrap())?;
+    let layer = args.value_of("layer").unwrap().parse()?;
+    export::export(&cache, layer, args.value_of("output").unwrap())
+}
+
+fn import_layer(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let filename = args.value_of("cache").unwrap();
+    let mut cache = read_cache(filename)?;
+    let layer = args.value_of("layer").unwrap().parse()?;
+    export::import(&mut cache, layer, args.value_of("input").unwrap())?;
+    cache.dump_with(
+        args.value_of("output").unwrap_or(filename),
+        compression(args),
+    )
+}
+
+fn inspect(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let filename = args.value_of("cache").unwrap();
+    match format::header(filename) {
+        Ok(header) => {
+            let compression = match Compression::from_code(header.compression) {
+                Some(Compression::None) => "uncompressed",
+                Some(Compression::Deflate) => "deflate",
+                None => "unknown compression",
+            };
+            println!(
+                "format:      version {}, {}, configuration hash {:016x}",
+                header.version, compression, header.config_hash
+            );
+        }
+        Err(CacheError::Unversioned) => println!("format:      legacy"),
+        Err(e) => return Err(format!("cannot read {}: {}", filename, e).into()),
+    }
+    let cache = read_cache(filename)?;
+    let (area, dimensions, shards) = (cache.area(), cache.dimensions(), cache.shards());
+    println!("area:        {:?}", area);
+    println!("dimensions:  {} x {}", dimensions.x, dimensions.y);
+    println!("sampling:    {:?}", cache.sampling());
+    println!(
+        "samples:     {} in shard(s) {:?} of {}",
+        cache.samples(),
+        shards.indices,
+        shards.count
+    );
+    println!(
+        "bailout:     {:?}, escape radius {}",
+        cache.bailout(),
+        cache.escape_radius()
+    );
+    println!("mapping:     {:?}", cache.mapping());
+    println!("projection:  {:?}", cache.projection());
+    println!("valid:       {}", cache.valid);
+    for (i, layer) in cache.layers.iter().enumerate() {
+        let spec = layer.spec();
+        let freqs = layer.frequencies();
+        println!(
+            "layer {}:     iterations {}..{}, {} plane(s)",
+            i,
+            spec.threshold(),
+            spec.iterations(),
+            spec.planes()
+        );
+        println!(
+            "  hits {}, maximum {}, empty {:.2}%",
+            freqs.total(),
+            freqs.max(),
+            freqs.empty() * 100.0
+        );
+        let percentiles: Vec<_> = [50.0, 90.0, 99.0, 99.9]
+            .iter()
+            .map(|&p| format!("p{} {}", p, freqs.percentile(p)))
+            .collect();
+        println!("  non-empty: {}", percentiles.join(", "));
+    }
+    Ok(())
+}
+
+fn cache_dir_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("cache-dir")
+        .takes_value(true)
+        .long("cache-dir")
+        .help("A directory of caches named after the parameters they were computed with")
+}
+
+fn render(cli: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config_filename = cli.value_of("config").unwrap();
+    let config_filestub = Path::new(config_filename).file_stem().unwrap();
+    let mut config = Configuration::load(config_filename)
+        .map_err(|e| format!("cannot load {}: {}", config_filename, e))?;
+
+    let mut cache_filename_default = format!("{}.cache", config_filestub.to_str().unwrap());
+    if let Some(shard) = cli.value_of("shard") {
+        config.shard = Shard::parse(shard)?;
+        cache_filename_default = format!(
+            "{}.{}-of-{}.cache",
+            config_filestub.to_str().unwrap(),
+            config.shard.index,
+            config.shard.count
+        );
+    }
+    let (mut cache, cache_filename) = match cli.value_of("cache-dir") {
+        Some(dir) => {
+            let store = Store::open(dir)?;
+            let source = fs::canonicalize(config_filename)?;
+            let cache = store.load(&config, &source.to_string_lossy())?;
+            (cache, store.path(&config))
+        }
+        None => {
+            let filename = cli
+                .value_of("cache")
+                .map(String::from)
+                .unwrap_or(cache_filename_default);
+            (Cache::load(&filename, &config), filename)
+        }
+    };
+
+    if !cache.valid {
+        info!("recreating cache");
+        cache.populate();
+        cache.dump_with(&cache_filename, compression(cli))?;
+    }
+
+    colorize(&cache, &config, cli.value_of("filename").unwrap())
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::builder()
-        .format_timestamp(None)
-        .init();
+    env_logger::builder().format_timestamp(None).init();
 
     let cli = App::new("Rostbrot")
         .version("0.1.0")
         .author("Matthias Wolf <m@sushinara.net>")
         .about("Generate Buddhabrot images")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("cache")
                 .takes_value(true)
                 .long("cache")
                 .help("A cache file to use; default: value of 'config' with extension 'cache'"),
         )
+        .arg(cache_dir_arg().conflicts_with("cache"))
+        .arg(compress_arg())
+        .arg(
+            Arg::with_name("shard")
+                .takes_value(true)
+                .long("shard")
+                .validator(|s| Shard::parse(&s).map(|_| ()).map_err(|e| e.to_string()))
+                .help(
+                    "Only sample the slice 'index/count' of the values of c, to be merged \
+                     with the other slices; default cache: 'config' with extension \
+                     'index-of-count.cache'",
+                ),
+        )
         .arg(
             Arg::with_name("config")
                 .takes_value(true)
@@ -39,21 +336,167 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .index(2)
                 .help("The output filename"),
         )
+        .subcommand(
+            cache_args(SubCommand::with_name("rebin"))
+                .about(
+                    "Downsample the histograms of a cache; render the result with the \
+                     original area and dimensions as 'sampling'",
+                )
+                .arg(
+                    Arg::with_name("factor")
+                        .takes_value(true)
+                        .required(true)
+                        .long("factor")
+                        .validator(|s| s.parse::<u16>().map(|_| ()).map_err(|e| e.to_string()))
+                        .help("The number of bins to combine along each axis"),
+                ),
+        )
+        .subcommand(
+            cache_args(SubCommand::with_name("crop"))
+                .about(
+                    "Cut out a rectangle of bins of a cache; render the result with the \
+                     original area and dimensions as 'sampling'",
+                )
+                .arg(
+                    Arg::with_name("columns")
+                        .takes_value(true)
+                        .required(true)
+                        .long("columns")
+                        .validator(|s| parse_range(&s).map(|_| ()))
+                        .help("The columns to keep as 'start:end'"),
+                )
+                .arg(
+                    Arg::with_name("rows")
+                        .takes_value(true)
+                        .required(true)
+                        .long("rows")
+                        .validator(|s| parse_range(&s).map(|_| ()))
+                        .help("The rows to keep as 'start:end', counted from the smallest imaginary part"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Add up the histograms of caches computed with the same configuration")
+                .arg(compress_arg())
+                .arg(
+                    Arg::with_name("inputs")
+                        .takes_value(true)
+                        .required(true)
+                        .multiple(true)
+                        .index(1)
+                        .help("The populated cache files to merge"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .takes_value(true)
+                        .required(true)
+                        .short("o")
+                        .long("output")
+                        .help("The cache file to write"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write the histogram of a layer to a .npy, .pfm or .fits file")
+                .arg(
+                    Arg::with_name("cache")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1)
+                        .help("A populated cache file"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .takes_value(true)
+                        .required(true)
+                        .index(2)
+                        .help("The file to write, with its format given by the extension"),
+                )
+                .arg(layer_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about(
+                    "Replace the histogram of a layer by one read from a .npy, .pfm or .fits \
+                     file, e.g., to colorize externally processed histograms",
+                )
+                .arg(
+                    Arg::with_name("cache")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1)
+                        .help("A populated cache file"),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .takes_value(true)
+                        .required(true)
+                        .index(2)
+                        .help("The file to read, with its format given by the extension"),
+                )
+                .arg(layer_arg())
+                .arg(
+                    Arg::with_name("output")
+                        .takes_value(true)
+                        .short("o")
+                        .long("output")
+                        .help("The cache file to write; default: the cache read"),
+                )
+                .arg(compress_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Print the parameters of a cache and statistics of its layers")
+                .arg(
+                    Arg::with_name("cache")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1)
+                        .help("A cache file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the caches of a cache directory, most recently used first")
+                .arg(cache_dir_arg().required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Remove unused caches from a cache directory")
+                .arg(cache_dir_arg().required(true))
+                .arg(
+                    Arg::with_name("older-than")
+                        .takes_value(true)
+                        .long("older-than")
+                        .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                        .help("Remove caches not used for this many days"),
+                )
+                .arg(
+                    Arg::with_name("max-size")
+                        .takes_value(true)
+                        .long("max-size")
+                        .validator(|s| parse_size(&s).map(|_| ()))
+                        .help("Remove the least recently used caches beyond this size, e.g., '20G'"),
+                ),
+        )
         .get_matches();
 
-    let config_filename = cli.value_of("config").unwrap();
-    let config_filestub = Path::new(config_filename).file_stem().unwrap();
-    let config = Configuration::load(config_filename).unwrap();
-
-    let cache_filename_default = format!("{}.cache", config_filestub.to_str().unwrap());
-    let cache_filename = cli.value_of("self").unwrap_or(&cache_filename_default);
-    let mut cache = Cache::load(&cache_filename, &config);
-
-    if !cache.valid {
-        info!("recreating cache");
-        cache.populate();
-        cache.dump(cache_filename).unwrap();
+    match cli.subcommand() {
+        ("rebin", Some(args)) => {
+            let factor = args.value_of("factor").unwrap().parse()?;
+            derive_cache(args, |c| c.rebin(factor))
+        }
+        ("crop", Some(args)) => {
+            let columns = parse_range(args.value_of("columns").unwrap())?;
+            let rows = parse_range(args.value_of("rows").unwrap())?;
+            derive_cache(args, |c| c.crop(columns, rows))
+        }
+        ("merge", Some(args)) => merge(args),
+        ("export", Some(args)) => export_layer(args),
+        ("import", Some(args)) => import_layer(args),
+        ("inspect", Some(args)) => inspect(args),
+        ("list", Some(args)) => list(args),
+        ("gc", Some(args)) => gc(args),
+        _ => render(&cli),
     }
-
-    colorize(&cache, &config, cli.value_of("filename").unwrap())
 }
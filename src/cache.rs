@@ -1,38 +1,329 @@
-#[path = "histogram.rs"]
-mod histogram;
-#[path = "mandelbrot.rs"]
-mod mandelbrot;
-
 use num_complex::Complex;
 use pbr::ProgressBar;
 use rayon::prelude::*;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
-use std::io::BufWriter;
+use std::io;
+use std::io::Read;
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
 
-use self::histogram::Histogram;
-use self::mandelbrot::{cardioid, first_bulb, mandelbrot};
+use format::{self, CacheError, Compression};
+use histogram::Histogram;
+use mandelbrot::{cardioid, first_bulb, mandelbrot_with, Bailout};
+use projection::{project, Mapping, Projection};
+use selection::{orbit_range, plain_orbit_bound, OrbitBound, Selection};
+use stats::Frequencies;
+use tone::{self, Curve, Level, ToneMap};
+use viewport::{Orientation, Viewport};
 
-#[derive(Debug, Deserialize)]
-pub struct Layer {
+/// Moments of the orbit lengths to accumulate per bin
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EscapeTime {
+    /// Accumulate the sum of orbit lengths to obtain the mean escape time
+    Mean,
+    /// Accumulate sums of orbit lengths and their squares to obtain the
+    /// variance, too
+    Variance,
+}
+
+/// The parameters of a layer that determine the contents of its histogram
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct LayerSpec {
     iterations: usize,
     #[serde(default)]
     threshold: usize,
+    #[serde(default)]
+    select: Selection,
+    #[serde(default, deserialize_with = "plain_orbit_bound")]
+    orbit_start: Option<OrbitBound>,
+    #[serde(default, deserialize_with = "plain_orbit_bound")]
+    orbit_end: Option<OrbitBound>,
+    #[serde(default)]
+    time_bins: Option<u16>,
+    #[serde(default)]
+    escape_time: Option<EscapeTime>,
+}
+
+impl LayerSpec {
+    /// The iteration limit of the orbits recorded
+    pub fn iterations(&self) -> usize {
+        self.iterations
+    }
+
+    /// The minimal length of the orbits recorded
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// The number of histogram planes, one per time bin
+    pub fn planes(&self) -> usize {
+        self.time_bins.map_or(1, |n| n.max(1) as usize)
+    }
+
+    /// Test if an orbit of escaping after `orbit.len()` iterations belongs
+    /// to this layer
+    fn accepts(&self, orbit: &[Complex<f64>], escape: Complex<f64>) -> bool {
+        self.threshold <= orbit.len()
+            && orbit.len() < self.iterations
+            && self.select.matches(orbit, escape)
+    }
+
+    /// Record the selected part of an orbit in a histogram
+    ///
+    /// With time bins, each iterate is recorded in the plane corresponding
+    /// to its position relative to the full orbit length.
+    ///
+    /// The orbit length is passed on as weight for the escape time moments.
+    fn record(&self, hist: &mut Histogram<f64>, orbit: &[Complex<f64>]) {
+        let range = orbit_range(orbit.len(), self.orbit_start, self.orbit_end);
+        let planes = self.planes();
+        let weight = orbit.len() as u64;
+        for (i, z) in orbit.iter().enumerate().take(range.end).skip(range.start) {
+            hist.fill_weighted(z.re, z.im, i * planes / orbit.len(), weight);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Layer {
+    #[serde(flatten)]
+    spec: LayerSpec,
     pub color: [u8; 3],
+    /// The count mapped to black; default: the noise floor
+    #[serde(default)]
+    pub black_point: Option<Level>,
+    /// The count mapped to full intensity; default: the largest count
+    #[serde(default)]
+    pub white_point: Option<Level>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct LayerData {
-    iterations: usize,
-    threshold: usize,
+    spec: LayerSpec,
+    #[serde(with = "format::bins")]
     pub data: Vec<u32>,
+    #[serde(with = "format::bins")]
+    sums: Vec<u64>,
+    #[serde(with = "format::bins")]
+    squares: Vec<u128>,
+    /// Whether the histogram has been populated, as opposed to being added
+    /// to a cache reusing other layers
+    #[serde(skip)]
+    complete: bool,
 }
 
 impl PartialEq<Layer> for LayerData {
     fn eq(&self, other: &Layer) -> bool {
-        self.iterations == other.iterations && self.threshold == other.threshold
+        self.spec == other.spec
+    }
+}
+
+impl LayerData {
+    fn new(spec: &LayerSpec, size: usize) -> LayerData {
+        let moments = match spec.escape_time {
+            None => 0,
+            Some(EscapeTime::Mean) => 1,
+            Some(EscapeTime::Variance) => 2,
+        };
+        LayerData {
+            spec: spec.clone(),
+            data: vec![0; size * spec.planes()],
+            sums: vec![0; if moments > 0 { size } else { 0 }],
+            squares: vec![0; if moments > 1 { size } else { 0 }],
+            complete: false,
+        }
+    }
+
+    pub fn spec(&self) -> &LayerSpec {
+        &self.spec
+    }
+
+    /// The number of consecutive planes of bins
+    pub fn planes(&self) -> usize {
+        self.spec.planes()
+    }
+
+    /// The largest pixel count, summed over all planes
+    pub fn max_count(&self) -> u32 {
+        match self.planes() {
+            1 => self.data.iter().copied().max().unwrap_or(0),
+            planes => (0..self.data.len() / planes)
+                .into_par_iter()
+                .map(|i| self.count(i))
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    /// The frequencies of the pixel counts, summed over all planes
+    pub fn frequencies(&self) -> Frequencies {
+        let table = (0..self.data.len() / self.planes())
+            .into_par_iter()
+            .fold(HashMap::new, |mut table, i| {
+                *table.entry(self.count(i)).or_insert(0) += 1;
+                table
+            })
+            .reduce(HashMap::new, |mut a, b| {
+                for (c, n) in b {
+                    *a.entry(c).or_insert(0) += n;
+                }
+                a
+            });
+        Frequencies::from(table)
+    }
+
+    /// Replace the bins with externally processed ones, dropping the escape
+    /// time moments, which no longer match
+    pub fn replace_data(&mut self, data: Vec<u32>) -> Result<(), Box<dyn Error>> {
+        if data.len() != self.data.len() {
+            return Err(format!("expected {} bins, got {}", self.data.len(), data.len()).into());
+        }
+        self.data = data;
+        self.sums.clear();
+        self.squares.clear();
+        Ok(())
+    }
+
+    /// Wrap the layer's bins and accumulators into a histogram over `area`
+    fn histogram(&mut self, area: Region, dimensions: Dimensions) -> Histogram<'_, f64> {
+        let LayerData {
+            ref mut data,
+            ref mut sums,
+            ref mut squares,
+            ..
+        } = *self;
+        let hist = Histogram::from_viewport(area.viewport(dimensions), &mut data[..]);
+        if sums.is_empty() {
+            return hist;
+        }
+        let squares = if squares.is_empty() {
+            None
+        } else {
+            Some(&mut squares[..])
+        };
+        hist.with_moments(&mut sums[..], squares)
+    }
+
+    /// The number of time bins, if the layer is time-resolved
+    pub fn time_bins(&self) -> Option<usize> {
+        match self.spec.planes() {
+            1 => None,
+            n => Some(n),
+        }
+    }
+
+    /// The total count of the pixel with index `idx`, summed over all planes
+    pub fn count(&self, idx: usize) -> u32 {
+        let size = self.data.len() / self.spec.planes();
+        self.data[idx..].iter().step_by(size).sum()
+    }
+
+    /// The count-weighted mean time bin of the pixel with index `idx`,
+    /// normalized to [0, 1]
+    pub fn mean_time(&self, idx: usize) -> Option<f32> {
+        let planes = self.time_bins()?;
+        let size = self.data.len() / planes;
+        let (total, weighted) = self.data[idx..]
+            .iter()
+            .step_by(size)
+            .enumerate()
+            .fold((0u64, 0u64), |(t, w), (i, &n)| {
+                (t + n as u64, w + i as u64 * n as u64)
+            });
+        if total == 0 {
+            return None;
+        }
+        Some(weighted as f32 / total as f32 / (planes - 1) as f32)
+    }
+
+    /// The mean orbit length of the orbits passing through the pixel with
+    /// index `idx`, if recorded
+    pub fn mean_escape(&self, idx: usize) -> Option<f64> {
+        let n = self.count(idx);
+        if self.sums.is_empty() || n == 0 {
+            return None;
+        }
+        Some(self.sums[idx] as f64 / n as f64)
+    }
+
+    /// The standard deviation of the orbit lengths of the orbits passing
+    /// through the pixel with index `idx`, if recorded
+    pub fn escape_deviation(&self, idx: usize) -> Option<f64> {
+        let mean = self.mean_escape(idx)?;
+        if self.squares.is_empty() {
+            return None;
+        }
+        let variance = self.squares[idx] as f64 / self.count(idx) as f64 - mean * mean;
+        Some(variance.max(0.0).sqrt())
+    }
+
+    /// The mean escape time of the pixel with index `idx` on a logarithmic
+    /// scale between the layer's threshold and iteration limit, normalized to
+    /// [0, 1]
+    pub fn relative_escape(&self, idx: usize) -> Option<f32> {
+        let mean = self.mean_escape(idx)?;
+        let lower = self.spec.threshold.max(1) as f64;
+        let upper = (self.spec.iterations as f64).max(lower + 1.0);
+        Some(((mean / lower).ln() / (upper / lower).ln()).clamp(0.0, 1.0) as f32)
+    }
+
+    /// Add the bins and accumulators of a layer with the same spec
+    fn merge(&mut self, other: &LayerData) -> Result<(), Box<dyn Error>> {
+        if self.sums.len() != other.sums.len() || self.squares.len() != other.squares.len() {
+            return Err("escape time moments were dropped from only one layer".into());
+        }
+        for (a, &b) in self.data.iter_mut().zip(other.data.iter()) {
+            *a = a.checked_add(b).ok_or("bin count overflows")?;
+        }
+        for (a, &b) in self.sums.iter_mut().zip(other.sums.iter()) {
+            *a = a.checked_add(b).ok_or("bin count overflows")?;
+        }
+        for (a, &b) in self.squares.iter_mut().zip(other.squares.iter()) {
+            *a = a.checked_add(b).ok_or("bin count overflows")?;
+        }
+        Ok(())
+    }
+
+    /// A copy of the layer where each of the `size` bins per plane is added
+    /// to the bin `target` gives for its index, if any, out of `new_size`
+    fn remap<F>(&self, size: usize, new_size: usize, target: F) -> Result<LayerData, Box<dyn Error>>
+    where
+        F: Fn(usize) -> Option<usize>,
+    {
+        let mut result = LayerData::new(&self.spec, new_size);
+        // Moments dropped by replacing the bins stay dropped
+        if self.sums.is_empty() {
+            result.sums.clear();
+        }
+        if self.squares.is_empty() {
+            result.squares.clear();
+        }
+        for (idx, t) in (0..size).filter_map(|i| target(i).map(|t| (i, t))) {
+            for plane in 0..self.spec.planes() {
+                let bin = &mut result.data[t + plane * new_size];
+                *bin = bin
+                    .checked_add(self.data[idx + plane * size])
+                    .ok_or("bin count overflows")?;
+            }
+            if !self.sums.is_empty() {
+                let sum = &mut result.sums[t];
+                *sum = sum
+                    .checked_add(self.sums[idx])
+                    .ok_or("bin count overflows")?;
+            }
+            if !self.squares.is_empty() {
+                let square = &mut result.squares[t];
+                *square = square
+                    .checked_add(self.squares[idx])
+                    .ok_or("bin count overflows")?;
+            }
+        }
+        result.complete = true;
+        Ok(result)
     }
 }
 
@@ -42,9 +333,216 @@ pub struct Area {
     pub y: [f64; 2],
 }
 
+/// The width of a view with a zoom of 1, covering the whole Mandelbrot set
+const ZOOM_WIDTH: f64 = 4.0;
+
+/// A rectangle of the plane around `center`, rotated counter-clockwise by
+/// `rotation` degrees, with `aspect` as ratio of width to height
+///
+/// The width is given either directly or as `zoom` relative to a width of 4.
+/// Without `aspect`, square pixels are assumed.  With a `shear`, the vertical
+/// edges are rotated by an additional angle in degrees.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct View {
+    pub center: [f64; 2],
+    #[serde(default)]
+    pub width: Option<f64>,
+    #[serde(default)]
+    pub zoom: Option<f64>,
+    #[serde(default)]
+    pub aspect: Option<f64>,
+    #[serde(default)]
+    pub rotation: f64,
+    #[serde(default)]
+    pub shear: f64,
+}
+
+impl View {
+    fn width(&self) -> f64 {
+        self.width
+            .unwrap_or_else(|| ZOOM_WIDTH / self.zoom.unwrap_or(1.0))
+    }
+}
+
+/// How to treat areas and dimensions that result in non-square pixels
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelCheck {
+    Ignore,
+    #[default]
+    Warn,
+    Error,
+}
+
+impl PixelCheck {
+    /// Apply the check to the ratio of pixel width to pixel height
+    fn apply(self, ratio: f64) -> Result<(), Box<dyn Error>> {
+        if (ratio - 1.0).abs() < 1e-3 {
+            return Ok(());
+        }
+        let msg = format!("pixels are not square, their aspect ratio is {:.4}", ratio);
+        match self {
+            PixelCheck::Ignore => Ok(()),
+            PixelCheck::Warn => {
+                warn!("{}", msg);
+                Ok(())
+            }
+            PixelCheck::Error => Err(msg.into()),
+        }
+    }
+}
+
+/// The number of pixels closest to `n`
+fn pixels(n: f64) -> Result<u16, Box<dyn Error>> {
+    let n = n.round().max(1.0);
+    if n > u16::MAX as f64 {
+        return Err(format!("derived dimension of {} pixels is too large", n).into());
+    }
+    Ok(n as u16)
+}
+
+/// A region of the plane, either as axis-aligned box or as rotated view
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Region {
+    Box(Area),
+    View(View),
+}
+
+/// Untagged form of `Region` to allow either form in configurations
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PlainRegion {
+    Box(Area),
+    View(View),
+}
+
+impl From<PlainRegion> for Region {
+    fn from(region: PlainRegion) -> Region {
+        match region {
+            PlainRegion::Box(a) => Region::Box(a),
+            PlainRegion::View(v) => Region::View(v),
+        }
+    }
+}
+
+/// Deserialize a value of an enum from the untagged form `U` in
+/// configurations, converted with `convert`
+///
+/// Binary formats that cannot guess the type of a value use the tagged form.
+pub fn untagged<'de, D, T, U, F>(deserializer: D, convert: F) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+    U: Deserialize<'de>,
+    F: FnOnce(U) -> T,
+{
+    if !deserializer.is_human_readable() {
+        return T::deserialize(deserializer);
+    }
+    U::deserialize(deserializer).map(convert)
+}
+
+/// Deserialize a region given by the fields of either form, see [`untagged`]
+fn plain_region<'de, D>(deserializer: D) -> Result<Region, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    untagged(deserializer, |r: PlainRegion| Region::from(r))
+}
+
+impl Region {
+    /// Derive missing dimensions, width, or aspect ratio, such that pixels are
+    /// square, and check the pixels of completely specified regions
+    ///
+    /// When deriving dimensions, the region is extended to keep pixels square
+    /// despite rounding.  A view's zoom is replaced by the width.
+    pub fn resolve(
+        &mut self,
+        dimensions: &mut Dimensions,
+        check: PixelCheck,
+    ) -> Result<(), Box<dyn Error>> {
+        let (nx, ny) = (dimensions.x as f64, dimensions.y as f64);
+        match *self {
+            Region::Box(ref mut a) => {
+                let (w, h) = (a.x[1] - a.x[0], a.y[1] - a.y[0]);
+                match (dimensions.x, dimensions.y) {
+                    (0, 0) => return Err("dimensions need at least one of x and y".into()),
+                    (0, _) => {
+                        dimensions.x = pixels(ny * w / h)?;
+                        let grow = (dimensions.x as f64 * h / ny - w) / 2.0;
+                        a.x = [a.x[0] - grow, a.x[1] + grow];
+                    }
+                    (_, 0) => {
+                        dimensions.y = pixels(nx * h / w)?;
+                        let grow = (dimensions.y as f64 * w / nx - h) / 2.0;
+                        a.y = [a.y[0] - grow, a.y[1] + grow];
+                    }
+                    _ => check.apply((w / nx) / (h / ny))?,
+                }
+            }
+            Region::View(ref mut v) => {
+                if v.width.is_some() == v.zoom.is_some() {
+                    return Err("a view needs exactly one of width and zoom".into());
+                }
+                if v.shear.is_nan() || v.shear.abs() >= 90.0 {
+                    return Err(format!(
+                        "a view's shear of {} degrees is not within (-90, 90)",
+                        v.shear
+                    )
+                    .into());
+                }
+                v.width = Some(v.width());
+                v.zoom = None;
+                match (v.aspect, dimensions.x, dimensions.y) {
+                    (_, 0, 0) => return Err("dimensions need at least one of x and y".into()),
+                    (None, 0, _) | (None, _, 0) => {
+                        return Err("a view needs an aspect ratio or both dimensions".into())
+                    }
+                    (None, _, _) => v.aspect = Some(nx / ny),
+                    (Some(aspect), 0, _) => {
+                        dimensions.x = pixels(ny * aspect)?;
+                        v.aspect = Some(dimensions.x as f64 / ny);
+                    }
+                    (Some(aspect), _, 0) => {
+                        dimensions.y = pixels(nx / aspect)?;
+                        v.aspect = Some(nx / dimensions.y as f64);
+                    }
+                    (Some(aspect), _, _) => check.apply(aspect * ny / nx)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The viewport covering the region with the given number of pixels
+    pub fn viewport(&self, dimensions: Dimensions) -> Viewport<f64> {
+        match *self {
+            Region::Box(a) => {
+                Viewport::new(a.x[0], a.x[1], dimensions.x, a.y[0], a.y[1], dimensions.y)
+            }
+            Region::View(v) => Viewport::rotated(
+                (v.center[0], v.center[1]),
+                v.width(),
+                v.width()
+                    / v.aspect
+                        .unwrap_or(dimensions.x as f64 / dimensions.y as f64),
+                v.rotation.to_radians(),
+                v.shear.to_radians(),
+                dimensions.x,
+                dimensions.y,
+            ),
+        }
+    }
+}
+
+/// The number of pixels along x and y
+///
+/// In configurations, either may be omitted to be derived from the area.
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Dimensions {
+    #[serde(default)]
     pub x: u16,
+    #[serde(default)]
     pub y: u16,
 }
 
@@ -54,36 +552,352 @@ impl Dimensions {
     }
 }
 
-#[derive(Deserialize)]
+/// The grid of values of `c` to sample
+///
+/// Needed whenever the histogram's area is not given in the coordinates of
+/// `c`, e.g., with a mapping other than the identity.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Sampling {
+    #[serde(deserialize_with = "plain_region")]
+    pub area: Region,
+    #[serde(default)]
+    pub dimensions: Option<Dimensions>,
+}
+
+impl Sampling {
+    fn viewport(&self) -> Viewport<f64> {
+        self.area.viewport(self.dimensions.unwrap())
+    }
+}
+
+/// A deterministic slice of the samples, consisting of every `count`-th
+/// value of `c` of the sampling grid, starting with the `index`-th
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Default for Shard {
+    fn default() -> Shard {
+        Shard { index: 0, count: 1 }
+    }
+}
+
+impl Shard {
+    /// Parse a shard given as `index/count`
+    pub fn parse(s: &str) -> Result<Shard, Box<dyn Error>> {
+        let parts: Vec<_> = s.split('/').collect();
+        if parts.len() != 2 {
+            return Err(format!("expected a shard as 'index/count', got '{}'", s).into());
+        }
+        let shard = Shard {
+            index: parts[0].trim().parse()?,
+            count: parts[1].trim().parse()?,
+        };
+        if shard.index >= shard.count {
+            return Err(format!("shard index {} is not below {}", shard.index, shard.count).into());
+        }
+        Ok(shard)
+    }
+}
+
+/// The shards of the samples recorded in a cache
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Shards {
+    pub count: u32,
+    pub indices: Vec<u32>,
+}
+
+impl From<Shard> for Shards {
+    fn from(shard: Shard) -> Shards {
+        Shards {
+            count: shard.count,
+            indices: vec![shard.index],
+        }
+    }
+}
+
+impl Shards {
+    /// Whether the `sample`-th value of `c` belongs to the shards
+    fn contains(&self, sample: usize) -> bool {
+        let index = (sample % self.count as usize) as u32;
+        self.indices.contains(&index)
+    }
+
+    /// Add the shards of `other`, where completing all shards is the same as
+    /// sampling everything in one go
+    fn merge(&mut self, other: &Shards) -> Result<(), Box<dyn Error>> {
+        if self.count != other.count {
+            return Err(format!(
+                "caches are split into {} and {} shards",
+                self.count, other.count
+            )
+            .into());
+        }
+        let common: Vec<_> = other
+            .indices
+            .iter()
+            .filter(|i| self.indices.contains(i))
+            .collect();
+        if !common.is_empty() {
+            return Err(format!("both caches contain the shards {:?}", common).into());
+        }
+        self.indices.extend(other.indices.iter());
+        self.indices.sort_unstable();
+        if self.indices.len() == self.count as usize {
+            *self = Shards::from(Shard::default());
+        }
+        Ok(())
+    }
+}
+
+fn default_escape_radius() -> f64 {
+    2.0
+}
+
+#[derive(Debug, Deserialize)]
 pub struct Configuration {
-    pub area: Area,
+    #[serde(deserialize_with = "plain_region")]
+    pub area: Region,
+    #[serde(default)]
+    pub bailout: Bailout,
     pub colorization: Color,
     pub dimensions: Dimensions,
+    #[serde(default = "default_escape_radius")]
+    pub escape_radius: f64,
     pub layers: Vec<Layer>,
+    #[serde(default)]
+    pub mapping: Mapping,
+    #[serde(default)]
+    pub non_square_pixels: PixelCheck,
+    #[serde(default)]
+    pub orientation: Orientation,
+    #[serde(default)]
+    pub projection: Projection,
+    #[serde(default)]
+    pub sampling: Option<Sampling>,
+    /// The slice of samples to compute, not part of configuration files
+    #[serde(skip)]
+    pub shard: Shard,
+}
+
+fn default_exponent() -> f32 {
+    1.0
+}
+
+fn default_noise_floor() -> f32 {
+    5.0
+}
+
+fn default_softening() -> f32 {
+    10.0
+}
+
+fn default_midpoint() -> f32 {
+    0.5
+}
+
+fn default_contrast() -> f32 {
+    8.0
+}
+
+fn default_exposure() -> f32 {
+    4.0
+}
+
+fn default_clip_limit() -> f32 {
+    3.0
 }
 
+/// The mapping of histogram counts onto intensities
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct Color {
+    #[serde(default)]
+    pub curve: Curve,
+    /// Counts up to this floor are treated as noise and stay black
+    #[serde(default = "default_noise_floor")]
+    pub noise_floor: f32,
+    /// The exponent of the power curve, also applied after the log curve
+    #[serde(default = "default_exponent")]
     pub exponent: f32,
+    /// The count up to which the asinh curve is roughly linear
+    #[serde(default = "default_softening")]
+    pub softening: f32,
+    /// The center of the sigmoid curve on a logarithmic scale in [0, 1]
+    #[serde(default = "default_midpoint")]
+    pub midpoint: f32,
+    /// The slope of the sigmoid curve
+    #[serde(default = "default_contrast")]
+    pub contrast: f32,
+    /// The value the largest count is mapped to before the filmic curve
+    #[serde(default = "default_exposure")]
+    pub exposure: f32,
+    /// The number of tiles along each axis to equalize separately, instead
+    /// of equalizing whole layers
+    #[serde(default)]
+    pub tiles: Option<u16>,
+    /// The largest frequency of an intensity level within a tile, relative
+    /// to the average
+    #[serde(default = "default_clip_limit")]
+    pub clip_limit: f32,
+}
+
+impl Color {
+    /// The tone curve selected, with its parameters, for a layer with the
+    /// black point `floor`, where only equalization takes the count
+    /// `frequencies`
+    pub fn tone_map<'a, F>(&self, frequencies: F, floor: f32) -> Box<dyn ToneMap + Sync>
+    where
+        F: FnOnce() -> &'a Frequencies,
+    {
+        match self.curve {
+            Curve::Linear => Box::new(tone::Linear),
+            Curve::Log => Box::new(tone::Log {
+                exponent: self.exponent,
+            }),
+            Curve::Power => Box::new(tone::Power {
+                exponent: self.exponent,
+            }),
+            Curve::Asinh => Box::new(tone::Asinh {
+                softening: self.softening,
+            }),
+            Curve::Sigmoid => Box::new(tone::Sigmoid {
+                midpoint: self.midpoint,
+                contrast: self.contrast,
+            }),
+            Curve::Filmic => Box::new(tone::Filmic {
+                exposure: self.exposure,
+            }),
+            Curve::Equalize => Box::new(tone::Equalize::new(frequencies(), floor)),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct Cache {
-    area: Area,
+    area: Region,
+    bailout: Bailout,
     dimensions: Dimensions,
+    escape_radius: f64,
     pub layers: Vec<LayerData>,
+    mapping: Mapping,
+    projection: Projection,
+    sampling: Sampling,
+    /// The number of values of `c` sampled
+    samples: u64,
+    shards: Shards,
     pub valid: bool,
 }
 
+/// The leading fields of caches written with versions 1 to 3 of the format,
+/// before the sample count, the shards, and the encoding of bins were added
+#[derive(Deserialize, Serialize)]
+struct DenseCache {
+    area: Region,
+    bailout: Bailout,
+    dimensions: Dimensions,
+    escape_radius: f64,
+    layers: Vec<DenseLayer>,
+    mapping: Mapping,
+    projection: Projection,
+    sampling: Sampling,
+}
+
+#[derive(Deserialize, Serialize)]
+struct DenseLayer {
+    spec: LayerSpec,
+    data: Vec<u32>,
+    sums: Vec<u64>,
+    squares: Vec<u128>,
+}
+
+/// The layout of caches written before the versioned format
+#[derive(Deserialize, Serialize)]
+struct LegacyCache {
+    area: Area,
+    dimensions: Dimensions,
+    layers: Vec<LegacyLayer>,
+    valid: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LegacyLayer {
+    iterations: usize,
+    threshold: usize,
+    data: Vec<u32>,
+}
+
+impl From<LegacyCache> for Cache {
+    fn from(legacy: LegacyCache) -> Cache {
+        let area = Region::Box(legacy.area);
+        let valid = legacy.valid;
+        Cache {
+            area,
+            bailout: Bailout::default(),
+            dimensions: legacy.dimensions,
+            escape_radius: default_escape_radius(),
+            layers: legacy
+                .layers
+                .into_iter()
+                .map(|l| LayerData {
+                    spec: LayerSpec {
+                        iterations: l.iterations,
+                        threshold: l.threshold,
+                        select: Selection::default(),
+                        orbit_start: None,
+                        orbit_end: None,
+                        time_bins: None,
+                        escape_time: None,
+                    },
+                    data: l.data,
+                    sums: vec![],
+                    squares: vec![],
+                    complete: valid,
+                })
+                .collect(),
+            mapping: Mapping::default(),
+            projection: Projection::default(),
+            sampling: Sampling {
+                area,
+                dimensions: Some(legacy.dimensions),
+            },
+            samples: if valid {
+                legacy.dimensions.size() as u64
+            } else {
+                0
+            },
+            shards: Shards::from(Shard::default()),
+            valid,
+        }
+    }
+}
+
+/// The parameters besides the layers that determine the contents of a cache
+#[derive(Debug, Serialize, PartialEq)]
+struct Key {
+    area: Region,
+    bailout: Bailout,
+    dimensions: Dimensions,
+    escape_radius: f64,
+    mapping: Mapping,
+    projection: Projection,
+    sampling: Sampling,
+    shards: Shards,
+}
+
+impl Key {
+    fn hash(&self) -> u64 {
+        format::hash(&bincode::serialize(self).unwrap())
+    }
+}
+
 impl PartialEq<Configuration> for Cache {
     fn eq(&self, other: &Configuration) -> bool {
-        if self.area != other.area
-            || self.dimensions != other.dimensions
-            || self.layers.len() != other.layers.len()
-        {
+        if self.key() != other.key() || self.layers.len() != other.layers.len() {
             return false;
         }
-        for (&ref a, &ref b) in self.layers.iter().zip(other.layers.iter()) {
+        for (a, b) in self.layers.iter().zip(other.layers.iter()) {
             if a != b {
                 return false;
             }
@@ -95,111 +909,564 @@ impl PartialEq<Configuration> for Cache {
 impl Configuration {
     pub fn load(filename: &str) -> Result<Configuration, Box<dyn Error>> {
         let file = File::open(filename)?;
-        let config = serde_yaml::from_reader(file)?;
+        Configuration::from_reader(file)
+    }
+
+    fn from_reader<R: Read>(reader: R) -> Result<Configuration, Box<dyn Error>> {
+        let mut config: Configuration = serde_yaml::from_reader(reader)?;
+        config
+            .area
+            .resolve(&mut config.dimensions, config.non_square_pixels)?;
+        if let Some(ref mut sampling) = config.sampling {
+            let mut dimensions = sampling.dimensions.unwrap_or(config.dimensions);
+            sampling.area.resolve(&mut dimensions, PixelCheck::Ignore)?;
+            sampling.dimensions = Some(dimensions);
+        }
+        let mapped = config.mapping != Mapping::Identity || !config.projection.is_identity();
+        if mapped && config.sampling.is_none() {
+            warn!("sampling the histogram area although it is given in mapped or projected coordinates");
+        }
         Ok(config)
     }
+
+    /// The viewport of the histograms as seen in images
+    pub fn viewport(&self) -> Viewport<f64> {
+        self.area
+            .viewport(self.dimensions)
+            .oriented(self.orientation)
+    }
+
+    fn key(&self) -> Key {
+        Key {
+            area: self.area,
+            bailout: self.bailout,
+            dimensions: self.dimensions,
+            escape_radius: self.escape_radius,
+            mapping: self.mapping,
+            projection: self.projection,
+            sampling: self.sampling(),
+            shards: Shards::from(self.shard),
+        }
+    }
+
+    /// An identifier of all parameters that determine the contents of a
+    /// cache, including the layers but not their colors
+    pub fn cache_id(&self) -> String {
+        let mut fnv = format::Fnv::default();
+        fnv.update(&bincode::serialize(&self.key()).unwrap());
+        for layer in &self.layers {
+            fnv.update(&bincode::serialize(&layer.spec).unwrap());
+        }
+        format!("{:016x}", fnv.finish())
+    }
+
+    /// The sampling grid, defaulting to the histogram's area and dimensions
+    pub fn sampling(&self) -> Sampling {
+        let sampling = self.sampling.unwrap_or(Sampling {
+            area: self.area,
+            dimensions: None,
+        });
+        Sampling {
+            dimensions: sampling.dimensions.or(Some(self.dimensions)),
+            ..sampling
+        }
+    }
 }
 
 impl Cache {
     pub fn new(c: &Configuration) -> Cache {
         Cache {
             area: c.area,
+            bailout: c.bailout,
             dimensions: c.dimensions,
+            escape_radius: c.escape_radius,
             layers: c
                 .layers
                 .iter()
+                .map(|l| LayerData::new(&l.spec, c.dimensions.size()))
+                .collect(),
+            mapping: c.mapping,
+            projection: c.projection,
+            sampling: c.sampling(),
+            samples: 0,
+            shards: Shards::from(c.shard),
+            valid: false,
+        }
+    }
+
+    fn key(&self) -> Key {
+        Key {
+            area: self.area,
+            bailout: self.bailout,
+            dimensions: self.dimensions,
+            escape_radius: self.escape_radius,
+            mapping: self.mapping,
+            projection: self.projection,
+            sampling: self.sampling,
+            shards: self.shards.clone(),
+        }
+    }
+
+    /// The region covered by the histograms
+    pub fn area(&self) -> Region {
+        self.area
+    }
+
+    pub fn dimensions(&self) -> Dimensions {
+        self.dimensions
+    }
+
+    /// The grid of values of `c` that was sampled
+    pub fn sampling(&self) -> Sampling {
+        self.sampling
+    }
+
+    /// The number of values of `c` sampled
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// The shards of the values of `c` sampled
+    pub fn shards(&self) -> &Shards {
+        &self.shards
+    }
+
+    pub fn bailout(&self) -> Bailout {
+        self.bailout
+    }
+
+    pub fn escape_radius(&self) -> f64 {
+        self.escape_radius
+    }
+
+    pub fn mapping(&self) -> Mapping {
+        self.mapping
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    /// Describe the first difference between the parameters and layers of two
+    /// caches, if any
+    fn mismatch(&self, other: &Cache) -> Option<String> {
+        let (a, b) = (self.key(), other.key());
+        let fields = [
+            ("area", format!("{:?}", a.area), format!("{:?}", b.area)),
+            (
+                "bailout",
+                format!("{:?}", a.bailout),
+                format!("{:?}", b.bailout),
+            ),
+            (
+                "dimensions",
+                format!("{:?}", a.dimensions),
+                format!("{:?}", b.dimensions),
+            ),
+            (
+                "escape radius",
+                format!("{:?}", a.escape_radius),
+                format!("{:?}", b.escape_radius),
+            ),
+            (
+                "mapping",
+                format!("{:?}", a.mapping),
+                format!("{:?}", b.mapping),
+            ),
+            (
+                "projection",
+                format!("{:?}", a.projection),
+                format!("{:?}", b.projection),
+            ),
+            (
+                "sampling",
+                format!("{:?}", a.sampling),
+                format!("{:?}", b.sampling),
+            ),
+            (
+                "number of layers",
+                format!("{}", self.layers.len()),
+                format!("{}", other.layers.len()),
+            ),
+        ];
+        for (name, x, y) in fields.iter() {
+            if x != y {
+                return Some(format!("{} differs: {} vs. {}", name, x, y));
+            }
+        }
+        for (i, (x, y)) in self.layers.iter().zip(other.layers.iter()).enumerate() {
+            if x.spec != y.spec {
+                return Some(format!(
+                    "layer {} differs: {:?} vs. {:?}",
+                    i, x.spec, y.spec
+                ));
+            }
+        }
+        None
+    }
+
+    /// Add the histograms and samples of `other`, which has to agree in all
+    /// parameters and layers, and cover different shards
+    pub fn merge(&mut self, other: &Cache) -> Result<(), Box<dyn Error>> {
+        if let Some(reason) = self.mismatch(other) {
+            return Err(reason.into());
+        }
+        if !self.valid || !other.valid {
+            return Err("only populated caches can be merged".into());
+        }
+        self.shards.merge(&other.shards)?;
+        for (a, b) in self.layers.iter_mut().zip(other.layers.iter()) {
+            a.merge(b)?;
+        }
+        self.samples += other.samples;
+        Ok(())
+    }
+
+    /// Read a cache from `filename`, migrating caches of the legacy layout
+    pub fn read(filename: &str) -> Result<Cache, CacheError> {
+        Cache::read_checked(filename, None)
+    }
+
+    /// Decode the data of a cache file written with format `version`
+    fn decode(version: u16, body: &mut dyn Read) -> bincode::Result<Cache> {
+        if version >= 4 {
+            return bincode::deserialize_from(body);
+        }
+        let dense: DenseCache = bincode::deserialize_from(&mut *body)?;
+        let samples: Option<u64> = match version {
+            1 => None,
+            _ => Some(bincode::deserialize_from(&mut *body)?),
+        };
+        let shards = match version {
+            1 | 2 => Shards::from(Shard::default()),
+            _ => bincode::deserialize_from(&mut *body)?,
+        };
+        let valid: bool = bincode::deserialize_from(body)?;
+        let grid = dense.sampling.dimensions.unwrap_or(dense.dimensions);
+        Ok(Cache {
+            area: dense.area,
+            bailout: dense.bailout,
+            dimensions: dense.dimensions,
+            escape_radius: dense.escape_radius,
+            layers: dense
+                .layers
+                .into_iter()
                 .map(|l| LayerData {
-                    iterations: l.iterations,
-                    threshold: l.threshold,
-                    data: vec![0; c.dimensions.size()],
+                    spec: l.spec,
+                    data: l.data,
+                    sums: l.sums,
+                    squares: l.squares,
+                    complete: valid,
                 })
                 .collect(),
-            valid: false,
+            mapping: dense.mapping,
+            projection: dense.projection,
+            sampling: dense.sampling,
+            samples: samples.unwrap_or(if valid { grid.size() as u64 } else { 0 }),
+            shards,
+            valid,
+        })
+    }
+
+    fn read_checked(filename: &str, config_hash: Option<u64>) -> Result<Cache, CacheError> {
+        let mut cache: Cache = match format::read_versioned(filename, config_hash, Cache::decode) {
+            Err(CacheError::Unversioned) => {
+                let file = io::BufReader::new(File::open(filename)?);
+                let legacy: LegacyCache = bincode::deserialize_from(file)?;
+                info!("migrating legacy cache in {}", filename);
+                Cache::from(legacy)
+            }
+            result => result?,
+        };
+        for layer in &mut cache.layers {
+            layer.complete = cache.valid;
+        }
+        Ok(cache)
+    }
+
+    /// Take over the complete layers of `other` that match layers of this
+    /// cache, returning the number of layers taken over
+    fn reuse(&mut self, other: Cache) -> usize {
+        let mut available: Vec<_> = other
+            .layers
+            .into_iter()
+            .filter(|l| l.complete)
+            .map(Some)
+            .collect();
+        let mut reused = 0;
+        for layer in &mut self.layers {
+            let found = available
+                .iter()
+                .position(|a| a.as_ref().is_some_and(|a| a.spec == layer.spec));
+            if let Some(idx) = found {
+                *layer = available[idx].take().unwrap();
+                reused += 1;
+            }
+        }
+        if reused > 0 {
+            self.samples = other.samples;
+        }
+        self.valid = self.layers.iter().all(|l| l.complete);
+        reused
+    }
+
+    /// Take over the complete layers matching layers of this cache from the
+    /// cache in `filename`, which has to agree in all other parameters,
+    /// returning the number of layers taken over
+    pub fn seed(&mut self, filename: &str) -> Result<usize, CacheError> {
+        let other = Cache::read_checked(filename, Some(self.key().hash()))?;
+        if other.key() != self.key() {
+            return Err(CacheError::Configuration);
         }
+        Ok(self.reuse(other))
     }
 
     pub fn load(filename: &str, config: &Configuration) -> Cache {
-        let file = match File::open(filename) {
-            Ok(f) => f,
-            _ => return Cache::new(config),
+        let hash = Some(config.key().hash());
+        let result = match Cache::read_checked(filename, hash) {
+            Err(e @ CacheError::Checksum) | Err(e @ CacheError::Decode(_)) => {
+                let backup = format::backup(filename);
+                warn!("cache in {} is damaged: {}, trying {}", filename, e, backup);
+                Cache::read_checked(&backup, hash)
+            }
+            result => result,
         };
-        let buf_reader = BufReader::new(file);
-        match bincode::deserialize_from(buf_reader) {
+        let reason = match result {
             Ok(c) => {
                 if c == *config {
                     info!("re-using cache in {}", filename);
-                    c
+                    return c;
+                } else if c.key() != config.key() {
+                    CacheError::Configuration.to_string()
                 } else {
-                    info!("overwriting cache in {}", filename);
-                    Cache::new(config)
+                    let mut cache = Cache::new(config);
+                    let reused = cache.reuse(c);
+                    info!(
+                        "re-using {} of {} layers of cache in {}",
+                        reused,
+                        cache.layers.len(),
+                        filename
+                    );
+                    return cache;
                 }
             }
-            _ => Cache::new(config),
-        }
+            Err(CacheError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                return Cache::new(config);
+            }
+            Err(e @ CacheError::Configuration) => e.to_string(),
+            Err(e) => {
+                warn!("not using cache in {}: {}", filename, e);
+                return Cache::new(config);
+            }
+        };
+        info!("overwriting cache in {}: {}", filename, reason);
+        Cache::new(config)
     }
 
     pub fn dump(&self, filename: &str) -> Result<(), Box<dyn Error>> {
-        let mut f = BufWriter::new(File::create(filename)?);
-        bincode::serialize_into(&mut f, &self)?;
+        self.dump_with(filename, Compression::None)
+    }
+
+    /// Write the cache to `filename`, compressing the data as given
+    pub fn dump_with(
+        &self,
+        filename: &str,
+        compression: Compression,
+    ) -> Result<(), Box<dyn Error>> {
+        format::write(filename, self, self.key().hash(), compression)?;
         Ok(())
     }
 
-    pub fn populate(&mut self) {
-        let max_iter = match self.layers.iter().map(|l| l.iterations).max() {
-            Some(n) => n,
-            None => 0,
+    /// A populated cache with the bins of this one added to the bins
+    /// `target` gives for their indices, if any
+    fn remap<F>(
+        &self,
+        area: Region,
+        dimensions: Dimensions,
+        target: F,
+    ) -> Result<Cache, Box<dyn Error>>
+    where
+        F: Fn(usize) -> Option<usize>,
+    {
+        if !self.valid {
+            return Err("the cache has not been populated".into());
+        }
+        let layers = self
+            .layers
+            .iter()
+            .map(|l| l.remap(self.dimensions.size(), dimensions.size(), &target))
+            .collect::<Result<_, _>>()?;
+        Ok(Cache {
+            area,
+            bailout: self.bailout,
+            dimensions,
+            escape_radius: self.escape_radius,
+            layers,
+            mapping: self.mapping,
+            projection: self.projection,
+            sampling: self.sampling,
+            samples: self.samples,
+            shards: self.shards.clone(),
+            valid: true,
+        })
+    }
+
+    /// Downsample the histograms by `factor` along both axes
+    ///
+    /// The sampling grid is kept, i.e., the result is used by configurations
+    /// that sample the original grid.
+    pub fn rebin(&self, factor: u16) -> Result<Cache, Box<dyn Error>> {
+        let (nx, ny) = (self.dimensions.x, self.dimensions.y);
+        if factor == 0 || nx % factor != 0 || ny % factor != 0 {
+            return Err(format!("dimensions {}x{} are not divisible by {}", nx, ny, factor).into());
+        }
+        let dimensions = Dimensions {
+            x: nx / factor,
+            y: ny / factor,
+        };
+        let (nx, f) = (nx as usize, factor as usize);
+        self.remap(self.area, dimensions, |idx| {
+            Some(idx / nx / f * (nx / f) + idx % nx / f)
+        })
+    }
+
+    /// Cut out the given columns and rows of the histograms, where rows are
+    /// counted from the smallest (local) imaginary part
+    ///
+    /// The sampling grid is kept, as with [`Cache::rebin`].
+    pub fn crop(&self, columns: Range<u16>, rows: Range<u16>) -> Result<Cache, Box<dyn Error>> {
+        let (nx, ny) = (self.dimensions.x, self.dimensions.y);
+        if columns.start >= columns.end || columns.end > nx {
+            return Err(format!("columns {:?} are not within 0..{}", columns, nx).into());
+        }
+        if rows.start >= rows.end || rows.end > ny {
+            return Err(format!("rows {:?} are not within 0..{}", rows, ny).into());
+        }
+        let viewport = self.area.viewport(self.dimensions);
+        let (x0, x1) = (columns.start as f64, columns.end as f64);
+        let (y0, y1) = (rows.start as f64, rows.end as f64);
+        let (fx, fy) = ((x1 - x0) / nx as f64, (y1 - y0) / ny as f64);
+        let area = match self.area {
+            Region::Box(_) => {
+                let (xmin, ymin) = viewport.to_plane(x0, y0);
+                let (xmax, ymax) = viewport.to_plane(x1, y1);
+                Region::Box(Area {
+                    x: [xmin, xmax],
+                    y: [ymin, ymax],
+                })
+            }
+            Region::View(v) => {
+                let (x, y) = viewport.to_plane((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+                let aspect = v.aspect.unwrap_or(nx as f64 / ny as f64);
+                Region::View(View {
+                    center: [x, y],
+                    width: Some(v.width() * fx),
+                    zoom: None,
+                    aspect: Some(aspect * fx / fy),
+                    ..v
+                })
+            }
         };
+        let dimensions = Dimensions {
+            x: columns.end - columns.start,
+            y: rows.end - rows.start,
+        };
+        let nx = nx as usize;
+        self.remap(area, dimensions, |idx| {
+            let (x, y) = ((idx % nx) as u16, (idx / nx) as u16);
+            if columns.contains(&x) && rows.contains(&y) {
+                Some(
+                    (x - columns.start) as usize
+                        + (y - rows.start) as usize * dimensions.x as usize,
+                )
+            } else {
+                None
+            }
+        })
+    }
 
-        let ranges: Vec<_> = self
+    /// Fill the histograms of all layers not complete yet
+    pub fn populate(&mut self) {
+        let specs: Vec<_> = self
             .layers
             .iter()
-            .map(|l| (l.iterations, l.threshold))
+            .filter(|l| !l.complete)
+            .map(|l| l.spec.clone())
             .collect();
+        let max_iter = specs.iter().map(|s| s.iterations).max().unwrap_or(0);
+        if specs.is_empty() {
+            self.valid = true;
+            return;
+        }
 
         let area = self.area;
         let dimensions = self.dimensions;
+        let radius = self.escape_radius;
+        let bailout = self.bailout;
+        let skip_set = bailout.confines_set(radius);
+        let identity = self.projection.is_identity() && self.mapping == Mapping::Identity;
+        let rows = self.projection.rows();
+        let mapping = self.mapping;
+        let grid = self.sampling.viewport();
+        let shards = &self.shards;
 
         let histos: Vec<_> = self
             .layers
             .iter_mut()
-            .map(|layer| {
-                Arc::new(Mutex::new(Histogram::new(
-                    area.x[0],
-                    area.x[1],
-                    dimensions.x,
-                    area.y[0],
-                    area.y[1],
-                    dimensions.y,
-                    &mut layer.data[..],
-                )))
-            })
+            .filter(|l| !l.complete)
+            .map(|layer| Arc::new(Mutex::new(layer.histogram(area, dimensions))))
+            .collect();
+
+        let centers: Vec<_> = grid
+            .centers()
+            .enumerate()
+            .filter(|&(k, _)| shards.contains(k))
+            .map(|(_, c)| c)
             .collect();
 
-        let mut pbar = ProgressBar::new(self.dimensions.size() as u64);
+        let mut pbar = ProgressBar::new(centers.len() as u64);
         pbar.show_counter = false;
         pbar.show_percent = false;
         pbar.show_speed = false;
         let msg = format!("{} iterations per pixel ", max_iter);
         pbar.message(&msg);
 
-        let centers: Vec<_> = histos[0].lock().unwrap().centers().collect();
         let pbarp = Arc::new(Mutex::new(pbar));
         let batchsize = 1000;
 
         centers.par_chunks(batchsize).for_each(|chunk| {
             for &(x, y) in chunk {
                 let c = Complex { re: x, im: y };
-                if cardioid(c) || first_bulb(c) {
+                if skip_set && (cardioid(c) || first_bulb(c)) {
                     continue;
                 }
-                let nums: Vec<_> = mandelbrot(c).take(max_iter).collect();
-                for (mutex, (maximum, minimum)) in histos.iter().zip(ranges.iter()) {
-                    if *minimum <= nums.len() && nums.len() < *maximum {
-                        let mut hist = mutex.lock().unwrap();
-                        for z in nums.iter() {
-                            hist.fill(z.re, z.im);
-                        }
-                    }
+                let mut seq = mandelbrot_with(c, radius, bailout);
+                let nums: Vec<_> = seq.by_ref().take(max_iter).collect();
+                let escape = seq.value();
+                let accepted: Vec<_> = specs.iter().map(|s| s.accepts(&nums, escape)).collect();
+                if !accepted.contains(&true) {
+                    continue;
+                }
+                // Points without image are not binned, but keep their place
+                // within the orbit
+                let points: Vec<_> = if identity {
+                    nums
+                } else {
+                    nums.iter()
+                        .map(|&z| {
+                            mapping
+                                .apply(project(&rows, z, c))
+                                .unwrap_or_else(|| Complex::new(f64::NAN, f64::NAN))
+                        })
+                        .collect()
+                };
+                for ((mutex, spec), _) in histos
+                    .iter()
+                    .zip(specs.iter())
+                    .zip(accepted)
+                    .filter(|&(_, a)| a)
+                {
+                    spec.record(&mut mutex.lock().unwrap(), &points);
                 }
             }
             pbarp.lock().unwrap().add(batchsize as u64);
@@ -207,6 +1474,10 @@ impl Cache {
 
         pbarp.lock().unwrap().finish();
 
+        for layer in &mut self.layers {
+            layer.complete = true;
+        }
+        self.samples = centers.len() as u64;
         self.valid = true;
     }
 }
@@ -214,34 +1485,100 @@ impl Cache {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
+    use std::io::{Seek, SeekFrom, Write};
     use tempfile::{tempdir, TempDir};
 
+    fn spec(iterations: usize, threshold: usize) -> LayerSpec {
+        LayerSpec {
+            iterations,
+            threshold,
+            select: Selection::default(),
+            orbit_start: None,
+            orbit_end: None,
+            time_bins: None,
+            escape_time: None,
+        }
+    }
+
+    fn layer(spec: LayerSpec) -> Layer {
+        Layer {
+            spec,
+            color: [0, 0, 0],
+            black_point: None,
+            white_point: None,
+        }
+    }
+
     #[test]
     fn layer_equality() {
-        let ld = LayerData {
-            iterations: 10,
-            threshold: 0,
-            data: vec![],
-        };
-        let l = Layer {
-            iterations: 10,
-            threshold: 0,
-            color: [0, 0, 0],
-        };
-        assert_eq!(ld, l);
-        let l2 = Layer {
-            iterations: 1,
-            threshold: 0,
-            color: [0, 0, 0],
-        };
-        assert_ne!(ld, l2);
-        let l2 = Layer {
-            iterations: 10,
-            threshold: 1,
-            color: [0, 0, 0],
-        };
-        assert_ne!(ld, l2);
+        let ld = LayerData::new(&spec(10, 0), 0);
+        assert_eq!(ld, layer(spec(10, 0)));
+        assert_ne!(ld, layer(spec(1, 0)));
+        assert_ne!(ld, layer(spec(10, 1)));
+        let mut s = spec(10, 0);
+        s.select.escape_quadrants = Some(vec![1]);
+        assert_ne!(ld, layer(s));
+        let mut s = spec(10, 0);
+        s.orbit_start = Some(OrbitBound::Fraction(0.9));
+        assert_ne!(ld, layer(s));
+        let mut s = spec(10, 0);
+        s.time_bins = Some(3);
+        assert_ne!(ld, layer(s));
+        let mut s = spec(10, 0);
+        s.escape_time = Some(EscapeTime::Mean);
+        assert_ne!(ld, layer(s));
+    }
+
+    #[test]
+    fn time_resolved_layer() {
+        let mut s = spec(10, 0);
+        s.time_bins = Some(3);
+        assert_eq!(s.planes(), 3);
+        let mut ld = LayerData::new(&s, 2);
+        {
+            let area = Region::Box(Area {
+                x: [0.0, 2.0],
+                y: [0.0, 1.0],
+            });
+            let mut hist = ld.histogram(area, Dimensions { x: 2, y: 1 });
+            let orbit: Vec<_> = (0..6)
+                .map(|i| Complex {
+                    re: if i < 4 { 0.5 } else { 1.5 },
+                    im: 0.5,
+                })
+                .collect();
+            s.record(&mut hist, &orbit);
+        }
+        assert_eq!(ld.data, vec![2, 0, 2, 0, 0, 2]);
+        assert_eq!(ld.time_bins(), Some(3));
+        assert_eq!(ld.count(0), 4);
+        assert_eq!(ld.count(1), 2);
+        assert_eq!(ld.max_count(), 4);
+        assert_eq!(ld.mean_time(0), Some(0.25));
+        assert_eq!(ld.mean_time(1), Some(1.0));
+        assert_eq!(ld.mean_escape(0), None);
+    }
+
+    #[test]
+    fn escape_time_layer() {
+        let mut s = spec(10, 1);
+        s.escape_time = Some(EscapeTime::Variance);
+        let mut ld = LayerData::new(&s, 2);
+        {
+            let area = Region::Box(Area {
+                x: [0.0, 2.0],
+                y: [0.0, 1.0],
+            });
+            let mut hist = ld.histogram(area, Dimensions { x: 2, y: 1 });
+            let z = Complex { re: 0.5, im: 0.5 };
+            s.record(&mut hist, &[z; 2]);
+            s.record(&mut hist, &[z; 6]);
+        }
+        assert_eq!(ld.data, vec![8, 0]);
+        assert_eq!(ld.mean_escape(0), Some(5.0));
+        assert_eq!(ld.mean_escape(1), None);
+        assert_eq!(ld.escape_deviation(0), Some(3.0_f64.sqrt()));
+        assert_eq!(ld.relative_escape(0), Some((5f64.ln() / 10f64.ln()) as f32));
     }
 
     #[test]
@@ -272,8 +1609,11 @@ mod tests {
                 layers:
                   - iterations: 10
                     color: [100, 100, 100]
+                    escape_time: variance
                   - iterations: 1
                     color: [10, 10, 10]
+                    orbit_start: 0.5
+                    orbit_end: 20
             "#
             )
             .unwrap();
@@ -286,8 +1626,119 @@ mod tests {
         let dir = tempdir().unwrap();
         let config = dump_config(&dir);
         assert_eq!(config.dimensions.x, 10);
-        assert_eq!(config.area.x[1], 2.0);
-        assert_eq!(config.layers[0].iterations, 10);
+        assert_eq!(
+            config.area,
+            Region::Box(Area {
+                x: [-2.0, 2.0],
+                y: [-1.0, 1.0]
+            })
+        );
+        assert_eq!(config.layers[0].spec.iterations, 10);
+        assert_eq!(config.escape_radius, 2.0);
+        assert_eq!(config.bailout, Bailout::Euclidean);
+        assert_eq!(config.layers[0].spec.orbit_start, None);
+        assert_eq!(
+            config.layers[1].spec.orbit_start,
+            Some(OrbitBound::Fraction(0.5))
+        );
+        assert_eq!(
+            config.layers[1].spec.orbit_end,
+            Some(OrbitBound::Absolute(20))
+        );
+    }
+
+    #[test]
+    fn load_view() {
+        let config = Configuration::from_reader(
+            r#"
+            dimensions: {x: 4, y: 2}
+            area: {center: [-0.5, 0], width: 2, aspect: 2, rotation: 90}
+            colorization: {exponent: 1.0}
+            layers: []
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+        let view = View {
+            center: [-0.5, 0.0],
+            width: Some(2.0),
+            zoom: None,
+            aspect: Some(2.0),
+            rotation: 90.0,
+            shear: 0.0,
+        };
+        assert_eq!(config.area, Region::View(view));
+        let centers: Vec<_> = config.area.viewport(config.dimensions).centers().collect();
+        assert!((centers[0].0 - -0.25).abs() < 1e-12);
+        assert!((centers[0].1 - -0.75).abs() < 1e-12);
+
+        let mut cache = Cache::new(&config);
+        cache.valid = true;
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("cache.bin");
+        let filename = path.to_str().unwrap();
+        cache.dump(filename).unwrap();
+        assert!(Cache::load(filename, &config).valid);
+    }
+
+    fn parse_area(area: &str, dimensions: &str, check: &str) -> Result<Configuration, String> {
+        let text = format!(
+            "{{area: {}, dimensions: {}, non_square_pixels: {}, colorization: {{exponent: 1.0}}, layers: []}}",
+            area, dimensions, check
+        );
+        Configuration::from_reader(text.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn derive_area() {
+        let config = parse_area("{center: [0, 0], zoom: 2}", "{x: 30, y: 20}", "error").unwrap();
+        match config.area {
+            Region::View(v) => {
+                assert_eq!(v.width, Some(2.0));
+                assert_eq!(v.zoom, None);
+                assert_eq!(v.aspect, Some(1.5));
+            }
+            _ => panic!("expected a view"),
+        }
+
+        let config = parse_area(
+            "{center: [0, 0], width: 3, aspect: 1.5}",
+            "{x: 30}",
+            "error",
+        )
+        .unwrap();
+        assert_eq!(config.dimensions, Dimensions { x: 30, y: 20 });
+
+        let config = parse_area("{x: [-2, 1], y: [-1, 1]}", "{y: 3}", "error").unwrap();
+        assert_eq!(config.dimensions, Dimensions { x: 5, y: 3 });
+        match config.area {
+            Region::Box(a) => assert!((a.x[1] - a.x[0] - 10.0 / 3.0).abs() < 1e-12),
+            _ => panic!("expected a box"),
+        }
+
+        assert!(parse_area("{x: [-2, 1], y: [-1, 1]}", "{x: 30, y: 30}", "ignore").is_ok());
+        assert!(parse_area("{x: [-2, 1], y: [-1, 1]}", "{x: 30, y: 30}", "warn").is_ok());
+        let err = parse_area("{x: [-2, 1], y: [-1, 1]}", "{x: 30, y: 30}", "error").unwrap_err();
+        assert!(err.contains("not square"));
+        let err = parse_area(
+            "{center: [0, 0], width: 3, aspect: 1}",
+            "{x: 30, y: 20}",
+            "error",
+        )
+        .unwrap_err();
+        assert!(err.contains("not square"));
+        assert!(parse_area(
+            "{center: [0, 0], width: 1, zoom: 2}",
+            "{x: 3, y: 2}",
+            "warn"
+        )
+        .is_err());
+        assert!(parse_area("{center: [0, 0], width: 1}", "{x: 3}", "warn").is_err());
+        let view = "{center: [0, 0], width: 1, aspect: 1, shear: -90}";
+        let err = parse_area(view, "{x: 3}", "warn").unwrap_err();
+        assert!(err.contains("shear"));
+        let view = "{center: [0, 0], width: 1, aspect: 1, shear: 89}";
+        assert!(parse_area(view, "{x: 3}", "warn").is_ok());
     }
 
     #[test]
@@ -295,7 +1746,7 @@ mod tests {
         let dir = tempdir().unwrap();
         let config = dump_config(&dir);
         let cache = Cache::new(&config);
-        assert_eq!(cache.valid, false);
+        assert!(!cache.valid);
         assert_eq!(cache.layers.len(), 2);
         {
             let path = dir.path().join("cache.bin");
@@ -316,13 +1767,40 @@ mod tests {
             let filename = path.to_str().unwrap();
             cache.valid = true;
             cache.dump(filename).unwrap();
-            config.layers[0].iterations = 100;
+            config.layers[0].spec.iterations = 100;
             let restored = Cache::load(filename, &config);
-            assert_eq!(restored.valid, false);
+            assert!(!restored.valid);
             assert_ne!(restored, cache);
         }
     }
 
+    #[test]
+    fn reuse_unchanged_layers() {
+        let dir = tempdir().unwrap();
+        let mut config = dump_config(&dir);
+        let mut cache = Cache::new(&config);
+        cache.valid = true;
+        cache.layers[1].data[0] = 12345;
+        let path = dir.path().join("cache.bin");
+        let filename = path.to_str().unwrap();
+        cache.dump(filename).unwrap();
+        config.layers[0].spec.iterations = 20;
+        let mut restored = Cache::load(filename, &config);
+        assert!(!restored.valid);
+        assert!(!restored.layers[0].complete);
+        assert!(restored.layers[1].complete);
+        assert_eq!(restored.layers[1].data[0], 12345);
+        restored.populate();
+        assert!(restored.valid);
+        assert_eq!(restored.layers[1].data[0], 12345);
+        assert!(restored.layers[0].data.iter().any(|&n| n > 0));
+
+        config.layers.swap(0, 1);
+        let restored = Cache::load(filename, &config);
+        assert!(!restored.valid);
+        assert_eq!(restored.layers[0].data[0], 12345);
+    }
+
     #[test]
     fn restore_modified_config() {
         let dir = tempdir().unwrap();
@@ -333,10 +1811,359 @@ mod tests {
             let filename = path.to_str().unwrap();
             cache.valid = true;
             cache.dump(filename).unwrap();
-            config.layers[0].iterations = 100;
+            config.layers[0].spec.iterations = 100;
             let restored = Cache::load(filename, &config);
-            assert_eq!(restored.valid, false);
+            assert!(!restored.valid);
             assert_ne!(restored, cache);
         }
     }
+
+    #[test]
+    fn restore_modified_escape() {
+        let dir = tempdir().unwrap();
+        let mut config = dump_config(&dir);
+        let mut cache = Cache::new(&config);
+        {
+            let path = dir.path().join("cache.bin");
+            let filename = path.to_str().unwrap();
+            cache.valid = true;
+            cache.dump(filename).unwrap();
+            config.escape_radius = 4.0;
+            let restored = Cache::load(filename, &config);
+            assert!(!restored.valid);
+            config.escape_radius = 2.0;
+            config.bailout = Bailout::Maximum;
+            let restored = Cache::load(filename, &config);
+            assert!(!restored.valid);
+            config.bailout = Bailout::Euclidean;
+            config.projection.zi_cr = 90.0;
+            let restored = Cache::load(filename, &config);
+            assert!(!restored.valid);
+            config.projection = Projection::default();
+            config.mapping = Mapping::Polar;
+            let restored = Cache::load(filename, &config);
+            assert!(!restored.valid);
+            config.mapping = Mapping::Identity;
+            config.sampling = Some(Sampling {
+                area: config.area,
+                dimensions: None,
+            });
+            let restored = Cache::load(filename, &config);
+            assert!(restored.valid);
+            config.sampling = Some(Sampling {
+                area: config.area,
+                dimensions: Some(Dimensions { x: 20, y: 10 }),
+            });
+            let restored = Cache::load(filename, &config);
+            assert!(!restored.valid);
+        }
+    }
+
+    #[test]
+    fn reject_corrupt_cache() {
+        let dir = tempdir().unwrap();
+        let config = dump_config(&dir);
+        let mut cache = Cache::new(&config);
+        cache.valid = true;
+        let path = dir.path().join("cache.bin");
+        let filename = path.to_str().unwrap();
+        cache.dump(filename).unwrap();
+        assert!(Cache::load(filename, &config).valid);
+        {
+            let mut f = std::fs::OpenOptions::new()
+                .write(true)
+                .open(filename)
+                .unwrap();
+            f.seek(SeekFrom::End(-1)).unwrap();
+            f.write_all(&[0]).unwrap();
+        }
+        match Cache::read(filename) {
+            Err(CacheError::Checksum) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(!Cache::load(filename, &config).valid);
+    }
+
+    #[test]
+    fn fall_back_to_backup() {
+        let dir = tempdir().unwrap();
+        let config = dump_config(&dir);
+        let mut cache = Cache::new(&config);
+        cache.valid = true;
+        let path = dir.path().join("cache.bin");
+        let filename = path.to_str().unwrap();
+        cache.dump(filename).unwrap();
+        cache.layers[0].data[0] = 1;
+        cache.dump(filename).unwrap();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(filename)
+            .and_then(|f| f.set_len(100))
+            .unwrap();
+        let restored = Cache::load(filename, &config);
+        assert!(restored.valid);
+        assert_eq!(restored.layers[0].data[0], 0);
+
+        File::create(filename).unwrap();
+        let restored = Cache::load(filename, &config);
+        assert!(restored.valid);
+        assert_eq!(restored.layers[0].data[0], 0);
+    }
+
+    #[test]
+    fn fall_back_on_damaged_length() {
+        let dir = tempdir().unwrap();
+        let config = dump_config(&dir);
+        let mut cache = Cache::new(&config);
+        cache.valid = true;
+        let smooth: Vec<u32> = (0..50).map(|i| 1000 + 3 * i).collect();
+        cache.layers[0].data = smooth.clone();
+        let path = dir.path().join("cache.bin");
+        let filename = path.to_str().unwrap();
+        cache.dump(filename).unwrap();
+        cache.layers[0].data[0] = 1;
+        cache.dump(filename).unwrap();
+
+        // The delta encoded bins are preceded by their variant and length
+        let mut bytes = std::fs::read(filename).unwrap();
+        let mut encoded = 2_u32.to_le_bytes().to_vec();
+        encoded.extend_from_slice(&50_u64.to_le_bytes());
+        let start = bytes
+            .windows(encoded.len())
+            .position(|w| w == &encoded[..])
+            .unwrap();
+        bytes[start + 4..start + 12].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(filename, &bytes).unwrap();
+        let restored = Cache::load(filename, &config);
+        assert!(restored.valid);
+        assert_eq!(restored.layers[0].data, smooth);
+    }
+
+    #[test]
+    fn migrate_legacy_cache() {
+        let dir = tempdir().unwrap();
+        let config = dump_config(&dir);
+        let legacy = LegacyCache {
+            area: Area {
+                x: [-2.0, 2.0],
+                y: [-1.0, 1.0],
+            },
+            dimensions: config.dimensions,
+            layers: vec![LegacyLayer {
+                iterations: 10,
+                threshold: 0,
+                data: vec![1; 50],
+            }],
+            valid: true,
+        };
+        let path = dir.path().join("cache.bin");
+        let filename = path.to_str().unwrap();
+        {
+            let f = File::create(filename).unwrap();
+            bincode::serialize_into(f, &legacy).unwrap();
+        }
+        let cache = Cache::read(filename).unwrap();
+        assert!(cache.valid);
+        assert_eq!(cache.layers[0].data, vec![1; 50]);
+        assert!(cache.key() == config.key());
+        assert_eq!(cache.layers[0], layer(spec(10, 0)));
+    }
+
+    #[test]
+    fn migrate_versioned_caches() {
+        let dir = tempdir().unwrap();
+        let config = dump_config(&dir);
+        let mut cache = Cache::new(&config);
+        cache.populate();
+        cache.layers[0].data[7] = 3;
+        let path = dir.path().join("cache.bin");
+        let filename = path.to_str().unwrap();
+        for version in 1..=4_u16 {
+            let dense = DenseCache {
+                area: cache.area,
+                bailout: cache.bailout,
+                dimensions: cache.dimensions,
+                escape_radius: cache.escape_radius,
+                layers: cache
+                    .layers
+                    .iter()
+                    .map(|l| DenseLayer {
+                        spec: l.spec.clone(),
+                        data: l.data.clone(),
+                        sums: l.sums.clone(),
+                        squares: l.squares.clone(),
+                    })
+                    .collect(),
+                mapping: cache.mapping,
+                projection: cache.projection,
+                sampling: cache.sampling,
+            };
+            let mut body = bincode::serialize(&dense).unwrap();
+            if version == 4 {
+                body = bincode::serialize(&cache).unwrap();
+            } else if version > 1 {
+                body.extend(bincode::serialize(&cache.samples).unwrap());
+            }
+            if version == 3 {
+                body.extend(bincode::serialize(&cache.shards).unwrap());
+            }
+            if version < 4 {
+                body.extend(bincode::serialize(&cache.valid).unwrap());
+            }
+            let header = (format::MAGIC, version, 1_u8, 0_u64, format::hash(&body));
+            let mut bytes = bincode::serialize(&header).unwrap();
+            bytes.extend(body);
+            std::fs::write(filename, bytes).unwrap();
+
+            let restored = Cache::load(filename, &config);
+            assert!(restored.valid);
+            assert_eq!(restored, cache);
+        }
+    }
+
+    #[test]
+    fn rebin_and_crop() {
+        let dir = tempdir().unwrap();
+        let config = dump_config(&dir);
+        let mut cache = Cache::new(&config);
+        assert!(cache.rebin(5).is_err());
+        cache.valid = true;
+        for layer in &mut cache.layers {
+            layer.data = (0..50).collect();
+        }
+        cache.layers[0].sums = vec![1; 50];
+        cache.layers[0].squares = vec![2; 50];
+        assert!(cache.rebin(3).is_err());
+
+        let rebinned = cache.rebin(5).unwrap();
+        assert!(rebinned.valid);
+        assert_eq!(rebinned.dimensions, Dimensions { x: 2, y: 1 });
+        assert_eq!(rebinned.area, cache.area);
+        assert_eq!(rebinned.sampling, cache.sampling);
+        let left: u32 = (0..5).flat_map(|y| (0..5).map(move |x| x + 10 * y)).sum();
+        assert_eq!(rebinned.layers[1].data, vec![left, 1225 - left]);
+        assert_eq!(rebinned.layers[0].sums, vec![25, 25]);
+        assert_eq!(rebinned.layers[0].squares, vec![50, 50]);
+        let mut saturated = cache.rebin(1).unwrap();
+        saturated.layers[0].sums = vec![u64::MAX; 50];
+        let msg = saturated.rebin(5).unwrap_err().to_string();
+        assert_eq!(msg, "bin count overflows");
+
+        assert!(cache.crop(2..11, 1..3).is_err());
+        let cropped = cache.crop(2..6, 1..3).unwrap();
+        assert_eq!(cropped.dimensions, Dimensions { x: 4, y: 2 });
+        assert_eq!(cropped.layers[1].data, vec![12, 13, 14, 15, 22, 23, 24, 25]);
+        assert_eq!(cropped.layers[0].sums, vec![1; 8]);
+        match cropped.area {
+            Region::Box(a) => {
+                assert!((a.x[0] + 1.2).abs() < 1e-12 && (a.x[1] - 0.4).abs() < 1e-12);
+                assert!((a.y[0] + 0.6).abs() < 1e-12 && (a.y[1] - 0.2).abs() < 1e-12);
+            }
+            _ => panic!("unexpected area {:?}", cropped.area),
+        }
+
+        cache.area = Region::View(View {
+            center: [0.0, 0.0],
+            width: Some(4.0),
+            zoom: None,
+            aspect: Some(2.0),
+            rotation: 90.0,
+            shear: 0.0,
+        });
+        let cropped = cache.crop(0..5, 0..5).unwrap();
+        match cropped.area {
+            Region::View(v) => {
+                assert!(v.center[0].abs() < 1e-12 && (v.center[1] + 1.0).abs() < 1e-12);
+                assert_eq!(v.width, Some(2.0));
+                assert_eq!(v.aspect, Some(1.0));
+            }
+            _ => panic!("unexpected area {:?}", cropped.area),
+        }
+
+        // Replaced bins are remapped without escape time moments, and not
+        // merged with ones that have them
+        let mut replaced = cache.rebin(1).unwrap();
+        replaced.layers[0].replace_data(vec![1; 50]).unwrap();
+        let rebinned = replaced.rebin(5).unwrap();
+        assert_eq!(rebinned.layers[0].data, vec![25, 25]);
+        assert!(rebinned.layers[0].sums.is_empty() && rebinned.layers[0].squares.is_empty());
+        assert_eq!(rebinned.layers[0].mean_escape(0), None);
+        let cropped = replaced.crop(2..6, 1..3).unwrap();
+        assert!(cropped.layers[0].sums.is_empty() && cropped.layers[0].squares.is_empty());
+        let msg = cache.rebin(1).unwrap().layers[0]
+            .merge(&replaced.layers[0])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(msg, "escape time moments were dropped from only one layer");
+    }
+
+    #[test]
+    fn merge_caches() {
+        let dir = tempdir().unwrap();
+        let mut config = dump_config(&dir);
+        config.shard = Shard { index: 0, count: 2 };
+        let mut a = Cache::new(&config);
+        config.shard.index = 1;
+        let mut b = Cache::new(&config);
+        assert!(a.merge(&b).is_err());
+        for (cache, n) in [(&mut a, 1), (&mut b, 2)].iter_mut() {
+            cache.valid = true;
+            cache.samples = 50;
+            cache.layers[0].data = vec![*n; 50];
+            cache.layers[0].sums = vec![*n as u64; 50];
+        }
+        a.merge(&b).unwrap();
+        assert_eq!(a.shards, Shards::from(Shard::default()));
+        let msg = a.merge(&b).unwrap_err().to_string();
+        assert_eq!(msg, "caches are split into 1 and 2 shards");
+        assert_eq!(a.samples(), 100);
+        assert_eq!(a.layers[0].data, vec![3; 50]);
+        assert_eq!(a.layers[0].sums, vec![3; 50]);
+        assert_eq!(a.layers[1].data, vec![0; 50]);
+        let mut c = Cache::new(&config);
+        c.layers[0].sums = vec![u64::MAX; 50];
+        let msg = c.layers[0].merge(&a.layers[0]).unwrap_err().to_string();
+        assert_eq!(msg, "bin count overflows");
+
+        config.layers[1].spec.iterations = 5;
+        let mut c = Cache::new(&config);
+        c.valid = true;
+        let msg = a.merge(&c).unwrap_err().to_string();
+        assert!(msg.starts_with("layer 1 differs"), "{}", msg);
+        config.layers.pop();
+        let msg = a.merge(&Cache::new(&config)).unwrap_err().to_string();
+        assert_eq!(msg, "number of layers differs: 2 vs. 1");
+        config.escape_radius = 3.0;
+        let msg = a.merge(&Cache::new(&config)).unwrap_err().to_string();
+        assert_eq!(msg, "escape radius differs: 2.0 vs. 3.0");
+        assert_eq!(a.samples(), 100);
+    }
+
+    #[test]
+    fn merge_shards() {
+        let dir = tempdir().unwrap();
+        let mut config = dump_config(&dir);
+        assert!(Shard::parse("3/3").is_err());
+        assert!(Shard::parse("1-3").is_err());
+        let mut full = Cache::new(&config);
+        full.populate();
+        config.shard = Shard::parse("0/3").unwrap();
+        let mut merged = Cache::new(&config);
+        merged.populate();
+        for index in &[2, 1] {
+            config.shard = Shard {
+                index: *index,
+                count: 3,
+            };
+            let mut shard = Cache::new(&config);
+            shard.populate();
+            assert!(merged.samples() > 0 && shard.samples() < full.samples());
+            merged.merge(&shard).unwrap();
+        }
+        assert_eq!(merged.samples(), 50);
+        assert_eq!(
+            bincode::serialize(&merged).unwrap(),
+            bincode::serialize(&full).unwrap()
+        );
+    }
 }
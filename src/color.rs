@@ -1,12 +1,94 @@
-#[path = "cache.rs"]
-mod cache;
-
 use rayon::prelude::*;
+use std::cell::OnceCell;
 use std::cmp;
 use std::error::Error;
 use std::f32;
 
 use cache::{Cache, Configuration};
+use tone::{Clahe, Curve, Level, ToneMap};
+
+/// The number of counts to tabulate intensities for, independent of the
+/// largest count
+const LUT_SIZE: u32 = 1 << 16;
+
+fn intensity(v: f32) -> u8 {
+    (v.clamp(0.0, 1.0) * 255.0) as u8
+}
+
+/// The intensities of the counts of a layer, tabulated for small counts and
+/// evaluated directly for larger ones
+struct Lut {
+    table: Vec<u8>,
+    curve: Box<dyn ToneMap + Sync>,
+    floor: f32,
+    white: f32,
+}
+
+impl Lut {
+    /// Map counts with `curve` from the black point `floor` up to `white`,
+    /// tabulating counts up to `max`
+    fn new(curve: Box<dyn ToneMap + Sync>, floor: f32, white: f32, max: u32) -> Lut {
+        let mut lut = Lut {
+            table: vec![],
+            curve,
+            floor,
+            white,
+        };
+        lut.table = (0..=max.min(LUT_SIZE - 1))
+            .into_par_iter()
+            .map(|i| lut.evaluate(i))
+            .collect();
+        lut
+    }
+
+    fn evaluate(&self, count: u32) -> u8 {
+        let mapped = self
+            .curve
+            .map(count as f32 - self.floor, self.white - self.floor);
+        intensity(mapped)
+    }
+
+    fn get(&self, count: u32) -> u8 {
+        match self.table.get(count as usize) {
+            Some(&v) => v,
+            None => self.evaluate(count),
+        }
+    }
+}
+
+/// The intensities of the counts of a layer
+enum Tones {
+    /// Intensities depending on the count only
+    Lut(Lut),
+    /// Intensities depending on the position, too, with the black point
+    Tiled(Clahe, f32),
+}
+
+impl Tones {
+    /// The intensity of the pixel at `x`, `y` with the count `count`
+    fn get(&self, x: u16, y: u16, count: u32) -> u8 {
+        match self {
+            Tones::Lut(lut) => lut.get(count),
+            Tones::Tiled(clahe, floor) => intensity(clahe.map(x, y, count as f32 - floor)),
+        }
+    }
+}
+
+/// Map a normalized time in [0, 1] onto a fully saturated hue, running from
+/// red for early iterates or short orbits over green to blue for late
+/// iterates or long orbits
+fn hue(t: f32) -> [u8; 3] {
+    let h = t.clamp(0.0, 1.0) * 4.0;
+    let sector = h.floor().min(3.0);
+    let rising = ((h - sector) * 255.0) as u8;
+    let falling = 255 - rising;
+    match sector as u8 {
+        0 => [255, rising, 0],
+        1 => [falling, 255, 0],
+        2 => [0, 255, rising],
+        _ => [0, falling, 255],
+    }
+}
 
 pub fn colorize(
     cache: &Cache,
@@ -17,28 +99,54 @@ pub fn colorize(
         image::ImageBuffer::new(config.dimensions.x as u32, config.dimensions.y as u32);
 
     info!("creating color LUT(s)");
-    let threshold = 5.0f32;
-    let luts: Vec<Vec<u8>> = cache
+    let colorization = &config.colorization;
+    let viewport = config.viewport();
+    let tones: Vec<Tones> = cache
         .layers
         .iter()
-        .map(|l| {
-            let m = l.data.iter().max().unwrap();
-            debug!("layer maximum: {}", m);
-            (0..=*m)
-                .into_par_iter()
-                .map(|i| {
-                    let upper = (*m as f32 - threshold).max(1.0).log2();
-                    let value = (i as f32 - threshold).max(1.0).log2();
-                    let mapped = if (config.colorization.exponent - 1.0).abs() < 1e6 {
-                        value / upper
-                    } else if (config.colorization.exponent - 0.5).abs() < 1e6 {
-                        (value / upper).sqrt()
-                    } else {
-                        (value / upper).powf(config.colorization.exponent)
-                    };
-                    (mapped * 255.0) as u8
-                })
-                .collect()
+        .zip(config.layers.iter())
+        .enumerate()
+        .map(|(i, (l, layer))| {
+            // The frequencies are only tabulated if needed, as this is costly
+            let table = OnceCell::new();
+            let frequencies = || table.get_or_init(|| l.frequencies());
+            let m = l.max_count();
+            let floor = match layer.black_point {
+                Some(level) => level.resolve(frequencies) as f32,
+                None => colorization.noise_floor,
+            };
+            let white = match layer.white_point {
+                Some(level) => level.resolve(frequencies),
+                None => m,
+            };
+            let white = (white as f32).max(floor + 1.0);
+            let describe = |level: Option<Level>, default: &str| match level {
+                Some(level) => level.to_string(),
+                None => default.to_string(),
+            };
+            info!(
+                "layer {}: black point {} ({}), white point {} ({}), maximum {}",
+                i,
+                floor,
+                describe(layer.black_point, "noise floor"),
+                white,
+                describe(layer.white_point, "maximum"),
+                m
+            );
+            if let (Curve::Equalize, Some(tiles)) = (colorization.curve, colorization.tiles) {
+                let count = |x, y| l.count(viewport.index(x, y)) as f32 - floor;
+                let clahe = Clahe::new(
+                    config.dimensions.x,
+                    config.dimensions.y,
+                    tiles,
+                    colorization.clip_limit,
+                    white - floor,
+                    count,
+                );
+                return Tones::Tiled(clahe, floor);
+            }
+            let curve = colorization.tone_map(frequencies, floor);
+            Tones::Lut(Lut::new(curve, floor, white, m))
         })
         .collect();
 
@@ -47,14 +155,17 @@ pub fn colorize(
         .enumerate_pixels_mut()
         .par_bridge()
         .for_each(|(x, y, pixel)| {
-            // for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-            let idx = (x + y * config.dimensions.x as u32) as usize;
-            // let v = 0;
+            let (x, y) = (x as u16, y as u16);
+            let idx = viewport.index(x, y);
             let mut color: [u8; 3] = [0, 0, 0];
-            for (i, lut) in luts.iter().enumerate() {
-                let v = lut[cache.layers[i].data[idx] as usize];
+            for (i, (layer, tones)) in cache.layers.iter().zip(tones.iter()).enumerate() {
+                let v = tones.get(x, y, layer.count(idx));
+                let tint = match layer.relative_escape(idx).or_else(|| layer.mean_time(idx)) {
+                    Some(t) => hue(t),
+                    None => config.layers[i].color,
+                };
                 for (j, col) in color.iter_mut().enumerate() {
-                    *col = cmp::max(*col, cmp::min(config.layers[i].color[j], v));
+                    *col = cmp::max(*col, cmp::min(tint[j], v));
                 }
             }
             *pixel = image::Rgb(color);
@@ -62,3 +173,26 @@ pub fn colorize(
     imgbuf.save(filename)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tone::Log;
+
+    #[test]
+    fn bounded_lut() {
+        let log = || Box::new(Log { exponent: 0.5 });
+        let small = Lut::new(log(), 5.0, 1000.0, 1000);
+        assert_eq!(small.table.len(), 1001);
+        assert_eq!((small.get(0), small.get(5), small.get(1000)), (0, 0, 255));
+        assert_eq!(small.get(2000), 255);
+
+        let large = Lut::new(log(), 5.0, 4e9, 4_000_000_000);
+        assert_eq!(large.table.len(), LUT_SIZE as usize);
+        let counts = [0, 6, 1000, LUT_SIZE - 1, LUT_SIZE, 1 << 20, 3_000_000_000];
+        let values: Vec<_> = counts.iter().map(|&c| large.get(c)).collect();
+        assert!(values.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(large.get(4_000_000_000), 255);
+        assert_eq!(large.get(LUT_SIZE), large.evaluate(LUT_SIZE));
+    }
+}
@@ -0,0 +1,516 @@
+//! Export and import of layer histograms in numeric formats of other tools
+//!
+//! Bins are written row by row, starting with the row of the smallest (local)
+//! imaginary part, with time-resolved planes as an additional, slowest axis
+//! where the format allows.  The coordinates of the bins are written as
+//! metadata: for FITS as a linear world coordinate system, for the other
+//! formats to a YAML file next to the export.
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use cache::{Cache, Dimensions, LayerSpec, Region};
+
+/// The file formats supported, chosen by file extension
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// NumPy arrays of unsigned 32 bit integers
+    Npy,
+    /// Portable float maps of the counts summed over all planes
+    Pfm,
+    /// FITS images of unsigned 32 bit integers
+    Fits,
+}
+
+impl Format {
+    pub fn from_path(filename: &str) -> Result<Format, Box<dyn Error>> {
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("npy") => Ok(Format::Npy),
+            Some("pfm") => Ok(Format::Pfm),
+            Some("fits") | Some("fit") => Ok(Format::Fits),
+            _ => Err(format!("cannot tell the format of {} from its extension", filename).into()),
+        }
+    }
+}
+
+/// The position of the bins in the complex plane
+#[derive(Debug, Serialize)]
+struct Axes {
+    /// The center of the first bin
+    origin: [f64; 2],
+    /// The offset between the centers of adjacent columns
+    column: [f64; 2],
+    /// The offset between the centers of adjacent rows
+    row: [f64; 2],
+}
+
+impl Axes {
+    fn new(area: Region, dimensions: Dimensions) -> Axes {
+        let viewport = area.viewport(dimensions);
+        let (x0, y0) = viewport.to_plane(0.5, 0.5);
+        let (x1, y1) = viewport.to_plane(1.5, 0.5);
+        let (x2, y2) = viewport.to_plane(0.5, 1.5);
+        Axes {
+            origin: [x0, y0],
+            column: [x1 - x0, y1 - y0],
+            row: [x2 - x0, y2 - y0],
+        }
+    }
+}
+
+/// The contents of the YAML file describing an export
+#[derive(Debug, Serialize)]
+struct Metadata<'a> {
+    area: Region,
+    dimensions: Dimensions,
+    planes: usize,
+    axes: Axes,
+    layer: &'a LayerSpec,
+    samples: u64,
+}
+
+/// Write layer `layer` of `cache` to `filename`
+pub fn export(cache: &Cache, layer: usize, filename: &str) -> Result<(), Box<dyn Error>> {
+    let data = cache
+        .layers
+        .get(layer)
+        .ok_or_else(|| format!("the cache has no layer {}", layer))?;
+    let dimensions = cache.dimensions();
+    let axes = Axes::new(cache.area(), dimensions);
+    let shape = [data.planes(), dimensions.y as usize, dimensions.x as usize];
+    let mut f = BufWriter::new(File::create(filename)?);
+    match Format::from_path(filename)? {
+        Format::Npy => write_npy(&mut f, &data.data, &shape)?,
+        Format::Pfm => {
+            let counts: Vec<_> = (0..dimensions.size()).map(|i| data.count(i)).collect();
+            write_pfm(&mut f, &counts, &shape[1..])?
+        }
+        Format::Fits => {
+            write_fits(&mut f, &data.data, &shape, &axes)?;
+            f.flush()?;
+            return Ok(());
+        }
+    }
+    f.flush()?;
+    let metadata = Metadata {
+        area: cache.area(),
+        dimensions,
+        planes: data.planes(),
+        axes,
+        layer: data.spec(),
+        samples: cache.samples(),
+    };
+    let sidecar = File::create(format!("{}.yaml", filename))?;
+    serde_yaml::to_writer(sidecar, &metadata)?;
+    Ok(())
+}
+
+/// Replace the bins of layer `layer` of `cache` by the ones in `filename`,
+/// rounded to the nearest count
+pub fn import(cache: &mut Cache, layer: usize, filename: &str) -> Result<(), Box<dyn Error>> {
+    let bytes = fs::read(filename)?;
+    let (values, shape) = match Format::from_path(filename)? {
+        Format::Npy => read_npy(&bytes)?,
+        Format::Pfm => read_pfm(&bytes)?,
+        Format::Fits => read_fits(&bytes)?,
+    };
+    let dimensions = cache.dimensions();
+    let data = cache
+        .layers
+        .get_mut(layer)
+        .ok_or_else(|| format!("the cache has no layer {}", layer))?;
+    let mut expected = vec![dimensions.y as usize, dimensions.x as usize];
+    if data.planes() > 1 {
+        expected.insert(0, data.planes());
+    }
+    // Layers without time bins are exported with or without a plane axis
+    let squeezed = match shape.split_first() {
+        Some((1, rest)) if shape.len() == 3 => rest,
+        _ => &shape[..],
+    };
+    if squeezed != &expected[..] {
+        return Err(format!("expected bins of shape {:?}, got {:?}", expected, shape).into());
+    }
+    let counts = values
+        .iter()
+        .map(|&v| {
+            if v.is_finite() && v >= 0.0 && v.round() <= u32::MAX as f64 {
+                Ok(v.round() as u32)
+            } else {
+                Err(format!("cannot use {} as count", v))
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    data.replace_data(counts)
+}
+
+fn write_npy<W: Write>(f: &mut W, data: &[u32], shape: &[usize]) -> Result<(), Box<dyn Error>> {
+    let shape: Vec<_> = shape.iter().map(|n| n.to_string()).collect();
+    let mut header = format!(
+        "{{'descr': '<u4', 'fortran_order': False, 'shape': ({}), }}",
+        shape.join(", ")
+    );
+    // The magic, version and length take 10 bytes, the header ends with a
+    // newline, and the data is aligned to 64 bytes
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+    f.write_all(b"\x93NUMPY\x01\x00")?;
+    f.write_all(&(header.len() as u16).to_le_bytes())?;
+    f.write_all(header.as_bytes())?;
+    for v in data {
+        f.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Values and shape of an array
+type Array = (Vec<f64>, Vec<usize>);
+
+/// Decode `n` little or big endian values of `size` bytes each
+fn decode(bytes: &[u8], kind: &str, n: usize, little: bool) -> Result<Vec<f64>, Box<dyn Error>> {
+    let size = kind[1..].parse::<usize>()?;
+    let bytes = n
+        .checked_mul(size)
+        .and_then(|len| bytes.get(..len))
+        .ok_or("too little data")?;
+    let values = bytes.chunks(size).map(|c| {
+        let mut b = [0; 8];
+        b[..size].copy_from_slice(c);
+        if !little {
+            b[..size].reverse();
+        }
+        match kind {
+            "u1" => f64::from(b[0]),
+            "i1" => f64::from(b[0] as i8),
+            "u2" => f64::from(u16::from_le_bytes([b[0], b[1]])),
+            "i2" => f64::from(i16::from_le_bytes([b[0], b[1]])),
+            "u4" => f64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            "i4" => f64::from(i32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            "f4" => f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            "u8" => u64::from_le_bytes(b) as f64,
+            "i8" => i64::from_le_bytes(b) as f64,
+            _ => f64::from_le_bytes(b),
+        }
+    });
+    Ok(values.collect())
+}
+
+/// The number of elements of an array of `shape`
+fn elements(shape: &[usize]) -> Result<usize, Box<dyn Error>> {
+    shape
+        .iter()
+        .try_fold(1_usize, |n, &m| n.checked_mul(m))
+        .ok_or_else(|| "too many elements".into())
+}
+
+fn read_npy(bytes: &[u8]) -> Result<Array, Box<dyn Error>> {
+    if bytes.len() < 10 || &bytes[..6] != b"\x93NUMPY" {
+        return Err("not a NumPy file".into());
+    }
+    let (start, len) = match bytes[6] {
+        1 => (10, u16::from_le_bytes([bytes[8], bytes[9]]) as usize),
+        _ if bytes.len() >= 12 => (
+            12,
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+        ),
+        _ => return Err("truncated NumPy header".into()),
+    };
+    let header = std::str::from_utf8(
+        bytes
+            .get(start..start + len)
+            .ok_or("truncated NumPy header")?,
+    )?;
+    let field = |name: &str| -> Result<&str, Box<dyn Error>> {
+        let key = format!("'{}':", name);
+        let pos = header
+            .find(&key)
+            .ok_or_else(|| format!("missing '{}'", name))?;
+        Ok(header[pos + key.len()..].trim_start())
+    };
+    if field("fortran_order")?.starts_with("True") {
+        return Err("arrays in Fortran order are not supported".into());
+    }
+    let descr = field("descr")?;
+    let descr = descr
+        .strip_prefix('\'')
+        .ok_or("malformed data type")?
+        .split('\'')
+        .next()
+        .unwrap_or("");
+    let kinds = ["u1", "i1", "u2", "i2", "u4", "i4", "u8", "i8", "f4", "f8"];
+    let (little, kind) = match (descr.get(..1), descr.get(1..)) {
+        (Some("<"), Some(kind)) | (Some("|"), Some(kind)) => (true, kind),
+        (Some(">"), Some(kind)) => (false, kind),
+        _ => (true, ""),
+    };
+    if !kinds.contains(&kind) {
+        return Err(format!("unsupported data type '{}'", descr).into());
+    }
+    let shape = field("shape")?;
+    let shape = shape
+        .strip_prefix('(')
+        .and_then(|s| s.find(')').map(|end| &s[..end]))
+        .ok_or("malformed shape")?;
+    let shape = shape
+        .split(',')
+        .map(|n| n.trim())
+        .filter(|n| !n.is_empty())
+        .map(|n| n.parse())
+        .collect::<Result<Vec<usize>, _>>()?;
+    let n = elements(&shape)?;
+    Ok((decode(&bytes[start + len..], kind, n, little)?, shape))
+}
+
+fn write_pfm<W: Write>(f: &mut W, data: &[u32], shape: &[usize]) -> Result<(), Box<dyn Error>> {
+    // Rows of portable float maps run from the bottom to the top, as stored
+    write!(f, "Pf\n{} {}\n-1.0\n", shape[1], shape[0])?;
+    for &v in data {
+        f.write_all(&(v as f32).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_pfm(bytes: &[u8]) -> Result<Array, Box<dyn Error>> {
+    let mut fields = vec![];
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while bytes.get(pos).is_some_and(|b| b.is_ascii_whitespace()) {
+            pos += 1;
+        }
+        let start = pos;
+        while bytes.get(pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+            pos += 1;
+        }
+        if start == pos {
+            return Err("truncated PFM header".into());
+        }
+        fields.push(std::str::from_utf8(&bytes[start..pos])?);
+    }
+    if fields[0] != "Pf" {
+        return Err("only grayscale portable float maps are supported".into());
+    }
+    let (nx, ny): (usize, usize) = (fields[1].parse()?, fields[2].parse()?);
+    let scale: f64 = fields[3].parse()?;
+    // A single whitespace character separates the header from the data
+    let data = bytes.get(pos + 1..).ok_or("missing PFM data")?;
+    let values = decode(data, "f4", elements(&[ny, nx])?, scale < 0.0)?;
+    Ok((values, vec![ny, nx]))
+}
+
+/// A FITS header card with `value` formatted as a fixed-format value
+fn card(keyword: &str, value: &str) -> String {
+    format!("{:<8}= {:>20}{:50}", keyword, value, "")
+}
+
+fn write_fits<W: Write>(
+    f: &mut W,
+    data: &[u32],
+    shape: &[usize],
+    axes: &Axes,
+) -> Result<(), Box<dyn Error>> {
+    let float = |x: f64| format!("{:.12E}", x);
+    // The image axes are always written, the plane axis only with time bins
+    let mut axis_sizes: Vec<_> = shape.iter().rev().collect();
+    if shape[0] == 1 {
+        axis_sizes.pop();
+    }
+    let mut cards = vec![
+        card("SIMPLE", "T"),
+        card("BITPIX", "32"),
+        card("NAXIS", &axis_sizes.len().to_string()),
+    ];
+    for (i, n) in axis_sizes.iter().enumerate() {
+        cards.push(card(&format!("NAXIS{}", i + 1), &n.to_string()));
+    }
+    cards.extend(vec![
+        card("BZERO", "2147483648"),
+        card("BSCALE", "1"),
+        card("CTYPE1", "'REAL'"),
+        card("CTYPE2", "'IMAG'"),
+        card("CRPIX1", &float(1.0)),
+        card("CRPIX2", &float(1.0)),
+        card("CRVAL1", &float(axes.origin[0])),
+        card("CRVAL2", &float(axes.origin[1])),
+        card("CD1_1", &float(axes.column[0])),
+        card("CD1_2", &float(axes.row[0])),
+        card("CD2_1", &float(axes.column[1])),
+        card("CD2_2", &float(axes.row[1])),
+        format!("{:80}", "END"),
+    ]);
+    let mut header = cards.concat();
+    while header.len() % 2880 != 0 {
+        header.push(' ');
+    }
+    f.write_all(header.as_bytes())?;
+    for &v in data {
+        f.write_all(&((v as i64 - (1 << 31)) as i32).to_be_bytes())?;
+    }
+    let written = data.len() * 4;
+    f.write_all(&vec![0; (2880 - written % 2880) % 2880])?;
+    Ok(())
+}
+
+fn read_fits(bytes: &[u8]) -> Result<Array, Box<dyn Error>> {
+    let mut keywords = vec![];
+    let mut end = None;
+    for (i, c) in bytes.chunks(80).enumerate() {
+        let c = std::str::from_utf8(c)?;
+        let keyword = c.get(..8).unwrap_or(c).trim();
+        if keyword == "END" {
+            end = Some((i * 80 / 2880 + 1) * 2880);
+            break;
+        }
+        if let (Some("= "), Some(value)) = (c.get(8..10), c.get(10..)) {
+            let value = value.split('/').next().unwrap_or("").trim();
+            keywords.push((keyword.to_string(), value.to_string()));
+        }
+    }
+    let start = end.ok_or("missing end of FITS header")?;
+    let value = |name: &str| keywords.iter().find(|k| k.0 == name).map(|k| k.1.as_str());
+    let number = |name: &str, default: f64| -> Result<f64, Box<dyn Error>> {
+        match value(name) {
+            Some(v) => Ok(v.replace('D', "E").parse()?),
+            None => Ok(default),
+        }
+    };
+    let kind = match value("BITPIX") {
+        Some("8") => "u1",
+        Some("16") => "i2",
+        Some("32") => "i4",
+        Some("64") => "i8",
+        Some("-32") => "f4",
+        Some("-64") => "f8",
+        v => return Err(format!("unsupported BITPIX {:?}", v).into()),
+    };
+    let naxis = number("NAXIS", 0.0)? as usize;
+    let mut shape = (1..=naxis)
+        .map(|i| number(&format!("NAXIS{}", i), 0.0).map(|n| n as usize))
+        .collect::<Result<Vec<_>, _>>()?;
+    shape.reverse();
+    let (zero, scale) = (number("BZERO", 0.0)?, number("BSCALE", 1.0)?);
+    let data = bytes.get(start..).ok_or("missing FITS data")?;
+    let values = decode(data, kind, elements(&shape)?, false)?
+        .into_iter()
+        .map(|v| zero + scale * v)
+        .collect();
+    Ok((values, shape))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::{Area, Configuration};
+    use tempfile::tempdir;
+
+    fn cache(x: u16, y: u16, time_bins: bool) -> Cache {
+        let yaml = format!(
+            "dimensions: {{x: {}, y: {}}}\narea: {{x: [0, {}], y: [-{}, 0]}}\n\
+             colorization: {{exponent: 1.0}}\nlayers:\n  - iterations: 10\n    \
+             color: [1, 2, 3]\n    time_bins: {}\n",
+            x,
+            y,
+            x,
+            y,
+            if time_bins { 2 } else { 1 }
+        );
+        let config: Configuration = serde_yaml::from_str(&yaml).unwrap();
+        let mut cache = Cache::new(&config);
+        cache.layers[0].data = (0..cache.layers[0].data.len() as u32).collect();
+        cache
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = tempdir().unwrap();
+        // Axes of a single bin are kept, also in formats dropping the plane
+        // axis of layers without time bins
+        for &(x, y) in &[(4, 3), (1, 3), (4, 1), (1, 1)] {
+            for (name, time_bins) in &[
+                ("a.npy", true),
+                ("b.fits", true),
+                ("c.pfm", false),
+                ("d.fits", false),
+                ("e.npy", false),
+            ] {
+                let path = dir.path().join(name);
+                let filename = path.to_str().unwrap();
+                let original = cache(x, y, *time_bins);
+                export(&original, 0, filename).unwrap();
+                let mut restored = cache(x, y, *time_bins);
+                restored.layers[0].data.iter_mut().for_each(|v| *v = 0);
+                import(&mut restored, 0, filename).unwrap();
+                let data = &original.layers[0].data;
+                assert_eq!(&restored.layers[0].data, data, "{} {}x{}", name, x, y);
+                assert!(import(&mut cache(x, y, !*time_bins), 0, filename).is_err());
+            }
+        }
+        assert!(Path::new(&dir.path().join("a.npy.yaml")).exists());
+        assert!(!Path::new(&dir.path().join("b.fits.yaml")).exists());
+    }
+
+    #[test]
+    fn malformed_headers() {
+        assert!(read_pfm(b"Pf\n4 3\n-1.0").is_err());
+        assert!(read_pfm(b"Pf\n4 3\n").is_err());
+        assert!(read_pfm(b"Pf\n65536 65536 65536 65536\n-1.0\n").is_err());
+        assert!(read_pfm(b"Pf\n4 3\n-1.0\n\0\0\0\0").is_err());
+
+        let npy = |header: &str| {
+            let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+            bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(header.as_bytes());
+            bytes
+        };
+        assert!(read_npy(b"\x93NUMPY\x02\x00\x10").is_err());
+        assert!(read_npy(&npy("{'descr': '<u4'")[..20]).is_err());
+        assert!(read_npy(&npy("{'fortran_order': False, 'descr':, 'shape': (1,)}")).is_err());
+        assert!(read_npy(&npy("{'fortran_order': False, 'descr': é, 'shape': (1,)}")).is_err());
+        assert!(read_npy(&npy("{'fortran_order': False, 'descr': '<u4', 'shape': )}")).is_err());
+        assert!(read_npy(&npy(
+            "{'fortran_order': False, 'descr': '<u4', 'shape': (2,)}"
+        ))
+        .is_err());
+        let header = "{'fortran_order': False, 'descr': '<u4', 'shape': (1,)}";
+        let mut bytes = npy(header);
+        bytes.extend_from_slice(&7_u32.to_le_bytes());
+        assert_eq!(read_npy(&bytes).unwrap(), (vec![7.0], vec![1]));
+
+        let fits = |cards: &[&str]| {
+            let mut header: String = cards.iter().map(|c| format!("{:80}", c)).collect();
+            header.truncate(header.trim_end().len());
+            header.into_bytes()
+        };
+        assert!(read_fits(&fits(&["SIMPLE  = T", "BITPIX  = 8", "NAXIS   = 0"])).is_err());
+        assert!(read_fits(&fits(&["SIMPLE  = T", "BITPIX  = 8", "END"])).is_err());
+        assert!(read_fits(&fits(&["SIMPLEé = T", "BITPIX é 8", "END"])).is_err());
+        assert!(read_fits(&fits(&["BITPIX", "NAXIS   =", "END"])).is_err());
+    }
+
+    #[test]
+    fn fits_header() {
+        let mut bytes = vec![];
+        let axes = Axes::new(
+            Region::Box(Area {
+                x: [0.0, 4.0],
+                y: [-3.0, 0.0],
+            }),
+            Dimensions { x: 4, y: 3 },
+        );
+        write_fits(&mut bytes, &[0; 12], &[1, 3, 4], &axes).unwrap();
+        assert_eq!(bytes.len(), 2 * 2880);
+        let header = std::str::from_utf8(&bytes[..2880]).unwrap();
+        assert!(header.starts_with("SIMPLE  =                    T"));
+        assert!(header.contains("NAXIS   =                    2"));
+        assert!(header.contains("CRVAL2  =    -2.500000000000E0"));
+        assert!(header.contains("CD2_2   =     1.000000000000E0"));
+        assert!(header.contains("CD1_2   =     0.000000000000E0"));
+    }
+}
@@ -0,0 +1,713 @@
+//! The on-disk layout of cache files
+//!
+//! A cache file starts with a fixed-size header, followed by the bincode
+//! encoded cache.  The header identifies the file, the version of the layout,
+//! the configuration the cache was computed for, and carries a checksum of
+//! the data following it.
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+
+/// The first bytes of every versioned cache file
+pub const MAGIC: [u8; 8] = *b"ROSTBROT";
+
+/// The version of the layout written
+pub const VERSION: u16 = 5;
+
+/// The oldest version of the layout that can still be read
+pub const MIN_VERSION: u16 = 1;
+
+/// The first version with a compression field in the header
+const COMPRESSION_VERSION: u16 = 5;
+
+/// The type of the elements stored in the layer histograms
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Element {
+    U32 = 1,
+}
+
+impl Element {
+    fn from_code(code: u8) -> Option<Element> {
+        match code {
+            1 => Some(Element::U32),
+            _ => None,
+        }
+    }
+}
+
+/// The compression of the data following the header
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    Deflate = 1,
+}
+
+impl Compression {
+    pub fn from_code(code: u8) -> Option<Compression> {
+        match code {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// The header of a cache file
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Header {
+    magic: [u8; 8],
+    pub version: u16,
+    pub element: u8,
+    pub compression: u8,
+    /// The hash of the parameters of a cache besides its layers
+    pub config_hash: u64,
+    /// The hash of the uncompressed data
+    pub checksum: u64,
+}
+
+/// The header of versions without compression
+#[derive(Deserialize)]
+struct UncompressedHeader {
+    magic: [u8; 8],
+    version: u16,
+    element: u8,
+    config_hash: u64,
+    checksum: u64,
+}
+
+impl From<UncompressedHeader> for Header {
+    fn from(h: UncompressedHeader) -> Header {
+        Header {
+            magic: h.magic,
+            version: h.version,
+            element: h.element,
+            compression: Compression::None as u8,
+            config_hash: h.config_hash,
+            checksum: h.checksum,
+        }
+    }
+}
+
+/// The reasons a cache file could not be read
+#[derive(Debug)]
+pub enum CacheError {
+    Io(io::Error),
+    /// The file does not start with a header
+    Unversioned,
+    /// The file was written with an unsupported layout version
+    Version(u16),
+    /// The histograms hold elements of an unknown type
+    Element(u8),
+    /// The data is compressed with an unknown method
+    Compression(u8),
+    /// The cache was computed for a different configuration
+    Configuration,
+    /// The data does not match the checksum of the header
+    Checksum,
+    /// The data could not be decoded
+    Decode(bincode::Error),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CacheError::Io(ref e) => write!(f, "{}", e),
+            CacheError::Unversioned => write!(f, "missing cache header"),
+            CacheError::Version(v) => write!(
+                f,
+                "unsupported format version {} (expected {} to {})",
+                v, MIN_VERSION, VERSION
+            ),
+            CacheError::Element(e) => write!(f, "unknown element type {}", e),
+            CacheError::Compression(c) => write!(f, "unknown compression {}", c),
+            CacheError::Configuration => write!(f, "computed for a different configuration"),
+            CacheError::Checksum => write!(f, "checksum mismatch, the data is corrupted"),
+            CacheError::Decode(ref e) => write!(f, "malformed data: {}", e),
+        }
+    }
+}
+
+impl Error for CacheError {}
+
+impl From<io::Error> for CacheError {
+    fn from(e: io::Error) -> CacheError {
+        CacheError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for CacheError {
+    fn from(e: bincode::Error) -> CacheError {
+        CacheError::Decode(e)
+    }
+}
+
+/// An incremental 64 bit FNV-1a hash
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The 64 bit FNV-1a hash of `bytes`
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut fnv = Fnv::default();
+    fnv.update(bytes);
+    fnv.finish()
+}
+
+/// Hashes everything passing through the wrapped reader or writer
+struct Hashing<T> {
+    inner: T,
+    fnv: Fnv,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Hashing<T> {
+        Hashing {
+            inner,
+            fnv: Fnv::default(),
+        }
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.fnv.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.fnv.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The file holding the previous generation of `filename`
+pub fn backup(filename: &str) -> String {
+    format!("{}.bak", filename)
+}
+
+/// Write `value` with a header to `filename`
+///
+/// The data is written to a temporary file next to `filename`, synced to disk
+/// and then renamed, so that `filename` always holds a complete cache.  The
+/// previous generation is kept as [`backup`].
+pub fn write<T: Serialize>(
+    filename: &str,
+    value: &T,
+    config_hash: u64,
+    compression: Compression,
+) -> Result<(), CacheError> {
+    let temporary = format!("{}.{}.tmp", filename, process::id());
+    let result = write_synced(&temporary, value, config_hash, compression).and_then(|_| {
+        if Path::new(filename).exists() {
+            rotate(filename, &backup(filename))?;
+        }
+        fs::rename(&temporary, filename)?;
+        sync_parent(filename);
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Keep the current contents of `filename` as `backup`, without removing
+/// `filename` itself
+fn rotate(filename: &str, backup: &str) -> io::Result<()> {
+    if let Err(e) = fs::remove_file(backup) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    if fs::hard_link(filename, backup).is_err() {
+        fs::copy(filename, backup)?;
+    }
+    Ok(())
+}
+
+/// Make renames within the directory of `filename` durable
+#[cfg(unix)]
+fn sync_parent(filename: &str) {
+    let parent = match Path::new(filename).parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    };
+    if let Err(e) = File::open(parent).and_then(|d| d.sync_all()) {
+        warn!("failed to sync directory {}: {}", parent.display(), e);
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_: &str) {}
+
+fn write_synced<T: Serialize>(
+    filename: &str,
+    value: &T,
+    config_hash: u64,
+    compression: Compression,
+) -> Result<(), CacheError> {
+    let mut header = Header {
+        magic: MAGIC,
+        version: VERSION,
+        element: Element::U32 as u8,
+        compression: compression as u8,
+        config_hash,
+        checksum: 0,
+    };
+    let mut f = BufWriter::new(File::create(filename)?);
+    bincode::serialize_into(&mut f, &header)?;
+    // The checksum covers the uncompressed data
+    header.checksum = match compression {
+        Compression::None => {
+            let mut body = Hashing::new(&mut f);
+            bincode::serialize_into(&mut body, value)?;
+            body.fnv.finish()
+        }
+        Compression::Deflate => {
+            let encoder = ZlibEncoder::new(&mut f, flate2::Compression::default());
+            let mut body = Hashing::new(encoder);
+            bincode::serialize_into(&mut body, value)?;
+            body.inner.finish()?;
+            body.fnv.finish()
+        }
+    };
+    f.seek(SeekFrom::Start(0))?;
+    bincode::serialize_into(&mut f, &header)?;
+    let f = f.into_inner().map_err(|e| e.into_error())?;
+    f.sync_all()?;
+    Ok(())
+}
+
+fn read_header<R: Read + Seek>(f: &mut R) -> Result<Header, CacheError> {
+    let mut magic = [0; 8];
+    // A file truncated within the header is damaged, not unreadable
+    f.read_exact(&mut magic).map_err(damaged)?;
+    if magic != MAGIC {
+        return Err(CacheError::Unversioned);
+    }
+    let version: u16 = bincode::deserialize_from(&mut *f)?;
+    f.seek(SeekFrom::Start(0))?;
+    if version < COMPRESSION_VERSION {
+        let header: UncompressedHeader = bincode::deserialize_from(f)?;
+        return Ok(Header::from(header));
+    }
+    Ok(bincode::deserialize_from(f)?)
+}
+
+/// Read the header of `filename`, of any version
+pub fn header(filename: &str) -> Result<Header, CacheError> {
+    read_header(&mut BufReader::new(File::open(filename)?))
+}
+
+/// Read a value written by [`write`] from `filename`
+///
+/// If `config_hash` is given, the file is rejected before reading any data
+/// when it was written for a different configuration.
+pub fn read<T: DeserializeOwned>(
+    filename: &str,
+    config_hash: Option<u64>,
+) -> Result<T, CacheError> {
+    read_versioned(filename, config_hash, |version, body| {
+        if version != VERSION {
+            return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "cannot decode format version {}",
+                version
+            ))));
+        }
+        bincode::deserialize_from(body)
+    })
+}
+
+/// Read a value written by [`write`] with any supported version of the
+/// layout from `filename`, where `decode` reads the data of a given version
+///
+/// The `config_hash` is only compared for the current version, as the
+/// parameters hashed changed between versions.
+pub fn read_versioned<T, F>(
+    filename: &str,
+    config_hash: Option<u64>,
+    decode: F,
+) -> Result<T, CacheError>
+where
+    F: FnOnce(u16, &mut dyn Read) -> bincode::Result<T>,
+{
+    let mut f = BufReader::new(File::open(filename)?);
+    let header = read_header(&mut f)?;
+    if header.version < MIN_VERSION || header.version > VERSION {
+        return Err(CacheError::Version(header.version));
+    }
+    if Element::from_code(header.element).is_none() {
+        return Err(CacheError::Element(header.element));
+    }
+    let compression = match Compression::from_code(header.compression) {
+        Some(c) => c,
+        None => return Err(CacheError::Compression(header.compression)),
+    };
+    if header.version == VERSION && config_hash.is_some_and(|h| h != header.config_hash) {
+        return Err(CacheError::Configuration);
+    }
+    // The data is verified before decoding it, as decoding trusts the lengths
+    // it reads
+    let start = f.stream_position()?;
+    let checksum = {
+        let mut body = Hashing::new(body_reader(&mut f, compression));
+        io::copy(&mut body, &mut io::sink()).map_err(damaged)?;
+        body.fnv.finish()
+    };
+    if checksum != header.checksum {
+        return Err(CacheError::Checksum);
+    }
+    f.seek(SeekFrom::Start(start))?;
+    let value = decode(header.version, &mut body_reader(&mut f, compression))?;
+    Ok(value)
+}
+
+/// The data following the header of a file
+fn body_reader<'a, R: Read + 'a>(f: R, compression: Compression) -> Box<dyn Read + 'a> {
+    match compression {
+        Compression::None => Box::new(f),
+        Compression::Deflate => Box::new(ZlibDecoder::new(f)),
+    }
+}
+
+/// Errors of reading truncated or malformed data as damaged data, other
+/// errors as unreadable files
+fn damaged(e: io::Error) -> CacheError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => {
+            CacheError::Decode(Box::new(bincode::ErrorKind::Io(e)))
+        }
+        _ => CacheError::Io(e),
+    }
+}
+
+/// Serialization of histogram bins in the smallest of three layouts: dense,
+/// only the non-zero bins with the number of zeros preceding each, or the
+/// differences between consecutive bins as variable-length integers
+pub mod bins {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::convert::TryFrom;
+    use std::mem;
+
+    #[derive(Serialize)]
+    enum BinsRef<'a, T> {
+        Dense(&'a [T]),
+        Sparse {
+            len: u64,
+            skips: Vec<u32>,
+            values: Vec<T>,
+        },
+        Delta {
+            len: u64,
+            bytes: Vec<u8>,
+        },
+    }
+
+    #[derive(Deserialize)]
+    enum Bins<T> {
+        Dense(Vec<T>),
+        Sparse {
+            len: u64,
+            skips: Vec<u32>,
+            values: Vec<T>,
+        },
+        Delta {
+            len: u64,
+            bytes: Vec<u8>,
+        },
+    }
+
+    /// The zigzag encoded differences of consecutive values
+    fn deltas<T: Copy + Into<u128>>(bins: &[T]) -> impl Iterator<Item = u128> + '_ {
+        bins.iter().scan(0_u128, |previous, &b| {
+            let value = b.into();
+            let delta = value.wrapping_sub(*previous) as i128;
+            *previous = value;
+            Some(((delta << 1) ^ (delta >> 127)) as u128)
+        })
+    }
+
+    /// The number of bytes of `n` as LEB128
+    fn varint_len(n: u128) -> usize {
+        (128 - n.leading_zeros() as usize).max(1).div_ceil(7)
+    }
+
+    pub fn serialize<S, T>(bins: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Copy + Default + PartialEq + Into<u128> + Serialize,
+    {
+        let zero = T::default();
+        let filled = bins.iter().filter(|&&b| b != zero).count();
+        let dense = mem::size_of_val(bins);
+        let sparse = filled * (mem::size_of::<T>() + mem::size_of::<u32>());
+        let delta: usize = deltas(bins).map(varint_len).sum();
+        if delta < dense && delta < sparse {
+            let mut bytes = Vec::with_capacity(delta);
+            for mut n in deltas(bins) {
+                while n >= 0x80 {
+                    bytes.push((n as u8) | 0x80);
+                    n >>= 7;
+                }
+                bytes.push(n as u8);
+            }
+            let encoded: BinsRef<T> = BinsRef::Delta {
+                len: bins.len() as u64,
+                bytes,
+            };
+            return encoded.serialize(serializer);
+        }
+        if sparse >= dense {
+            return BinsRef::Dense(bins).serialize(serializer);
+        }
+        let mut skips = Vec::with_capacity(filled);
+        let mut values = Vec::with_capacity(filled);
+        let mut skip = 0_u32;
+        for &b in bins {
+            // Overly long runs of zeros are split by storing a zero
+            if b != zero || skip == u32::MAX {
+                skips.push(skip);
+                values.push(b);
+                skip = 0;
+            } else {
+                skip += 1;
+            }
+        }
+        BinsRef::Sparse {
+            len: bins.len() as u64,
+            skips,
+            values,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Copy + Default + Into<u128> + TryFrom<u128> + Deserialize<'de>,
+    {
+        match Bins::deserialize(deserializer)? {
+            Bins::Dense(bins) => Ok(bins),
+            Bins::Sparse { len, skips, values } => {
+                if skips.len() != values.len() {
+                    return Err(D::Error::custom("sparse bins of different lengths"));
+                }
+                // The length is only trusted as far as it can be allocated
+                let mut bins = Vec::new();
+                bins.try_reserve_exact(len as usize)
+                    .map_err(|_| D::Error::custom("too many sparse bins"))?;
+                bins.resize(len as usize, T::default());
+                let mut idx = 0;
+                for (&skip, &value) in skips.iter().zip(values.iter()) {
+                    idx += skip as usize;
+                    match bins.get_mut(idx) {
+                        Some(b) => *b = value,
+                        None => return Err(D::Error::custom("sparse bins out of range")),
+                    }
+                    idx += 1;
+                }
+                Ok(bins)
+            }
+            Bins::Delta { len, bytes } => {
+                // Each bin takes at least one byte
+                let mut bins = Vec::with_capacity((len as usize).min(bytes.len()));
+                let (mut previous, mut n, mut shift) = (0_u128, 0_u128, 0);
+                for &byte in &bytes {
+                    if shift >= 128 {
+                        return Err(D::Error::custom("delta encoded bin too long"));
+                    }
+                    n |= u128::from(byte & 0x7f) << shift;
+                    shift += 7;
+                    if byte & 0x80 != 0 {
+                        continue;
+                    }
+                    let delta = ((n >> 1) as i128) ^ -((n & 1) as i128);
+                    previous = previous.wrapping_add(delta as u128);
+                    let value = T::try_from(previous)
+                        .map_err(|_| D::Error::custom("delta encoded bin out of range"))?;
+                    bins.push(value);
+                    n = 0;
+                    shift = 0;
+                }
+                if bins.len() as u64 != len || shift != 0 {
+                    return Err(D::Error::custom("delta encoded bins of wrong length"));
+                }
+                Ok(bins)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+
+    #[test]
+    fn fnv_reference() {
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn header_checks() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let filename = path.to_str().unwrap();
+        let data: Vec<u32> = (0..100).collect();
+        write(filename, &data, 42, Compression::None).unwrap();
+        let restored: Vec<u32> = read(filename, Some(42)).unwrap();
+        assert_eq!(restored, data);
+        let restored: Vec<u32> = read(filename, None).unwrap();
+        assert_eq!(restored, data);
+        match read::<Vec<u32>>(filename, Some(7)) {
+            Err(CacheError::Configuration) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(!Path::new(&backup(filename)).exists());
+
+        let mut f = OpenOptions::new().write(true).open(filename).unwrap();
+        f.seek(SeekFrom::End(-1)).unwrap();
+        f.write_all(&[255]).unwrap();
+        match read::<Vec<u32>>(filename, Some(42)) {
+            Err(CacheError::Checksum) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        f.seek(SeekFrom::Start(8)).unwrap();
+        f.write_all(&[99, 0]).unwrap();
+        match read::<Vec<u32>>(filename, Some(42)) {
+            Err(CacheError::Version(99)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.write_all(b"NOTACACHE").unwrap();
+        match read::<Vec<u32>>(filename, Some(42)) {
+            Err(CacheError::Unversioned) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn keep_backup() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let filename = path.to_str().unwrap();
+        write(filename, &vec![1_u32], 1, Compression::None).unwrap();
+        write(filename, &vec![2_u32], 2, Compression::None).unwrap();
+        write(filename, &vec![3_u32], 3, Compression::None).unwrap();
+        let restored: Vec<u32> = read(filename, Some(3)).unwrap();
+        assert_eq!(restored, vec![3]);
+        let restored: Vec<u32> = read(&backup(filename), Some(2)).unwrap();
+        assert_eq!(restored, vec![2]);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Layer {
+        #[serde(with = "bins")]
+        data: Vec<u32>,
+        #[serde(with = "bins")]
+        squares: Vec<u128>,
+    }
+
+    #[test]
+    fn sparse_bins() {
+        let dense = Layer {
+            data: (0..100).collect(),
+            squares: vec![],
+        };
+        let encoded = bincode::serialize(&dense).unwrap();
+        assert_eq!(bincode::deserialize::<Layer>(&encoded).unwrap(), dense);
+        assert!(encoded.len() < 100 * 4 + 50);
+
+        let mut sparse = Layer {
+            data: vec![0; 1000],
+            squares: vec![0; 1000],
+        };
+        sparse.data[0] = 1;
+        sparse.data[999] = 2;
+        sparse.squares[500] = u128::MAX;
+        let encoded = bincode::serialize(&sparse).unwrap();
+        assert_eq!(bincode::deserialize::<Layer>(&encoded).unwrap(), sparse);
+        assert!(encoded.len() < 150);
+
+        // A damaged length is an error instead of an allocation failure,
+        // and files are verified before their data is decoded
+        let mut damaged = encoded.clone();
+        damaged[4..12].copy_from_slice(&(u64::MAX / 8).to_le_bytes());
+        assert!(bincode::deserialize::<Layer>(&damaged).is_err());
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sparse.bin");
+        let filename = path.to_str().unwrap();
+        write(filename, &sparse, 42, Compression::None).unwrap();
+        let mut bytes = fs::read(filename).unwrap();
+        let start = bytes.len() - encoded.len();
+        bytes[start..][..encoded.len()].copy_from_slice(&damaged);
+        fs::write(filename, &bytes).unwrap();
+        assert!(matches!(
+            read::<Layer>(filename, Some(42)),
+            Err(CacheError::Checksum)
+        ));
+
+        let smooth = Layer {
+            data: (0..1000).map(|i| 1_000_000 + i * 3).collect(),
+            squares: (0..1000).map(|i| u128::MAX - i).collect(),
+        };
+        let encoded = bincode::serialize(&smooth).unwrap();
+        assert_eq!(bincode::deserialize::<Layer>(&encoded).unwrap(), smooth);
+        assert!(encoded.len() < 2100 + 50);
+    }
+
+    #[test]
+    fn compression() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let filename = path.to_str().unwrap();
+        let data = vec![7_u32; 10000];
+        write(filename, &data, 42, Compression::Deflate).unwrap();
+        assert!(fs::metadata(filename).unwrap().len() < 1000);
+        let restored: Vec<u32> = read(filename, Some(42)).unwrap();
+        assert_eq!(restored, data);
+        let uncompressed = format!("{}.plain", filename);
+        write(&uncompressed, &data, 42, Compression::None).unwrap();
+        assert!(fs::metadata(&uncompressed).unwrap().len() > 40000);
+    }
+}
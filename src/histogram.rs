@@ -1,87 +1,62 @@
-use num_traits::cast;
 use num_traits::Float;
-use std::iter;
 
-struct Binning<T> {
-    scale: T,
-    min: T,
-    num: u16,
-}
-
-impl<T> Binning<T>
-where
-    T: Float,
-{
-    fn bin(&self, n: T) -> Option<u16> {
-        match cast((n - self.min) * self.scale) {
-            Some(c) => {
-                if c >= self.num {
-                    None
-                } else {
-                    Some(c)
-                }
-            }
-            _ => None,
-        }
-    }
-
-    fn iter(&self) -> impl Iterator<Item = T> + '_ {
-        (0..self.num).map(move |n| {
-            self.min + (cast::<u16, T>(n).unwrap() + cast::<f32, T>(0.5).unwrap()) / self.scale
-        })
-    }
-}
+use viewport::Viewport;
 
+/// A histogram over the pixels of a viewport
+///
+/// The viewport describes the coordinates of the histogram, which need not be
+/// the coordinates the orbits were sampled with.
 pub struct Histogram<'a, T> {
-    xaxis: Binning<T>,
-    yaxis: Binning<T>,
+    viewport: Viewport<T>,
     bins: &'a mut [u32],
+    sums: Option<&'a mut [u64]>,
+    squares: Option<&'a mut [u128]>,
 }
 
 impl<'a, T> Histogram<'a, T>
 where
     T: Float,
 {
-    fn index(&self, x: usize, y: usize) -> usize {
-        x + y * self.xaxis.num as usize
-    }
-
-    pub fn centers(&self) -> impl Iterator<Item = (T, T)> + '_ {
-        self.yaxis
-            .iter()
-            .flat_map(move |y| self.xaxis.iter().zip(iter::repeat(y)))
+    /// Create a histogram over an existing viewport
+    pub fn from_viewport(viewport: Viewport<T>, bins: &'a mut [u32]) -> Histogram<'a, T> {
+        Histogram {
+            viewport,
+            bins,
+            sums: None,
+            squares: None,
+        }
     }
 
-    pub fn fill(&mut self, x: T, y: T) {
-        let nx = self.xaxis.bin(x);
-        let ny = self.yaxis.bin(y);
-        if nx.is_none() || ny.is_none() {
-            return;
+    /// Fill the bin at `x`, `y` of the given plane, where the bins hold
+    /// consecutive planes of the same dimensions, and add `weight` and its
+    /// square to the auxiliary accumulators of the bin, if present
+    ///
+    /// The accumulators are shared by all planes.
+    pub fn fill_weighted(&mut self, x: T, y: T, plane: usize, weight: u64) {
+        let idx = match self.viewport.locate(x, y) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let size = self.viewport.size();
+        self.bins[idx + plane * size] += 1;
+        if let Some(ref mut sums) = self.sums {
+            sums[idx] += weight;
+        }
+        if let Some(ref mut squares) = self.squares {
+            squares[idx] += weight as u128 * weight as u128;
         }
-        let idx = self.index(nx.unwrap() as usize, ny.unwrap() as usize);
-        self.bins[idx] += 1;
     }
 
-    pub fn new(
-        xmin: T,
-        xmax: T,
-        xnum: u16,
-        ymin: T,
-        ymax: T,
-        ynum: u16,
-        bins: &'a mut [u32],
+    /// Attach auxiliary accumulators for the sum and, optionally, the sum of
+    /// squares of the weights filled into each bin
+    pub fn with_moments(
+        mut self,
+        sums: &'a mut [u64],
+        squares: Option<&'a mut [u128]>,
     ) -> Histogram<'a, T> {
-        let xaxis = Binning {
-            scale: T::from(xnum).unwrap() / (xmax - xmin),
-            min: xmin,
-            num: xnum,
-        };
-        let yaxis = Binning {
-            scale: T::from(ynum).unwrap() / (ymax - ymin),
-            min: ymin,
-            num: ynum,
-        };
-        Histogram { xaxis, yaxis, bins }
+        self.sums = Some(sums);
+        self.squares = squares;
+        self
     }
 }
 
@@ -89,39 +64,43 @@ where
 mod tests {
     use super::*;
 
-    #[test]
-    fn binning_iter() {
-        let bins = Binning {
-            scale: 2.0,
-            min: 0.0,
-            num: 2,
-        };
-        let res: Vec<f64> = bins.iter().collect();
-        assert_eq!(res, vec![0.25, 0.75]);
-    }
-
-    #[test]
-    fn binning_fill() {
-        let bins = Binning {
-            scale: 1.0,
-            min: 0.0,
-            num: 2,
-        };
-        let b1 = bins.bin(0.1);
-        assert_eq!(b1, Some(0));
-        let b2 = bins.bin(5.0);
-        assert_eq!(b2, None);
+    fn viewport() -> Viewport<f64> {
+        Viewport::new(0.0, 1.0, 2, 0.0, 1.0, 1)
     }
 
     #[test]
     fn histogram_usage() {
         let data = &mut [0, 0];
-        let mut histo = Histogram::new(0.0, 1.0, 2, 0.0, 1.0, 1, data);
-        let centers: Vec<_> = histo.centers().collect();
-        assert_eq!(centers, vec![(0.25, 0.5), (0.75, 0.5)]);
-        histo.fill(-2.0, 3.0);
-        histo.fill(0.51, 0.1);
+        let mut histo = Histogram::from_viewport(viewport(), data);
+        histo.fill_weighted(-2.0, 3.0, 0, 0);
+        histo.fill_weighted(0.51, 0.1, 0, 0);
         assert_eq!(data[0], 0_u32);
         assert_eq!(data[1], 1_u32);
     }
+
+    #[test]
+    fn histogram_planes() {
+        let data = &mut [0; 4];
+        let mut histo = Histogram::from_viewport(viewport(), data);
+        histo.fill_weighted(0.1, 0.1, 1, 0);
+        histo.fill_weighted(0.6, 0.1, 0, 0);
+        assert_eq!(data, &[0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn histogram_moments() {
+        let data = &mut [0; 2];
+        let sums = &mut [0; 2];
+        let squares = &mut [0; 2];
+        {
+            let mut histo =
+                Histogram::from_viewport(viewport(), data).with_moments(sums, Some(squares));
+            histo.fill_weighted(0.1, 0.1, 0, 3);
+            histo.fill_weighted(0.2, 0.1, 0, 5);
+            histo.fill_weighted(0.7, 1.1, 0, 5);
+        }
+        assert_eq!(data, &[2, 0]);
+        assert_eq!(sums, &[8, 0]);
+        assert_eq!(squares, &[34, 0]);
+    }
 }
@@ -1,5 +1,6 @@
-extern crate image;
 extern crate bincode;
+extern crate flate2;
+extern crate image;
 #[macro_use]
 extern crate log;
 extern crate num_complex;
@@ -16,4 +17,13 @@ extern crate tempfile;
 
 pub mod cache;
 pub mod color;
+pub mod export;
+pub mod format;
+pub mod histogram;
 pub mod mandelbrot;
+pub mod projection;
+pub mod selection;
+pub mod stats;
+pub mod store;
+pub mod tone;
+pub mod viewport;
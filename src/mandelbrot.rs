@@ -1,10 +1,67 @@
 use num_complex::Complex;
 use num_traits::Float;
 
+/// The norm used to decide whether an orbit has escaped
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bailout {
+    /// The modulus |z|, i.e., a circle around the origin
+    #[default]
+    Euclidean,
+    /// max(|re|, |im|), i.e., a square around the origin
+    Maximum,
+    /// |re| + |im|, i.e., a diamond around the origin
+    Manhattan,
+    /// |re| only, i.e., a vertical strip
+    Real,
+    /// |im| only, i.e., a horizontal strip
+    Imaginary,
+}
+
+impl Bailout {
+    /// Test if `z` lies outside of the bailout region of radius `r`
+    pub fn escaped<T>(self, z: Complex<T>, r: T) -> bool
+    where
+        T: Float,
+    {
+        match self {
+            Bailout::Euclidean => z.norm_sqr() > r * r,
+            Bailout::Maximum => z.re.abs().max(z.im.abs()) > r,
+            Bailout::Manhattan => z.re.abs() + z.im.abs() > r,
+            Bailout::Real => z.re.abs() > r,
+            Bailout::Imaginary => z.im.abs() > r,
+        }
+    }
+
+    /// Test if no orbit of the Mandelbrot set can escape with radius `r`
+    ///
+    /// Orbits of the set stay within |z| <= 2, so points in the cardioid or
+    /// the first bulb can only be skipped if the bailout norm never exceeds
+    /// the modulus and the radius is at least 2.
+    pub fn confines_set<T>(self, r: T) -> bool
+    where
+        T: Float,
+    {
+        self != Bailout::Manhattan && r >= T::from(2.0).unwrap()
+    }
+}
+
 pub struct ComplexSequence<T> {
     z: Complex<T>,
     c: Complex<T>,
     r: T,
+    bailout: Bailout,
+}
+
+impl<T> ComplexSequence<T>
+where
+    T: Copy,
+{
+    /// The current value of the sequence, i.e., the escaping value once
+    /// iteration stopped
+    pub fn value(&self) -> Complex<T> {
+        self.z
+    }
 }
 
 impl<T> Iterator for ComplexSequence<T>
@@ -15,7 +72,7 @@ where
 
     fn next(&mut self) -> Option<Complex<T>> {
         self.z = self.z * self.z + self.c;
-        if self.z.norm_sqr() > self.r * self.r {
+        if self.bailout.escaped(self.z, self.r) {
             return None;
         }
         Some(self.z)
@@ -51,11 +108,18 @@ where
 }
 
 pub fn mandelbrot<T>(c: Complex<T>) -> ComplexSequence<T>
+where
+    T: Float,
+{
+    mandelbrot_with(c, T::from(2.0).unwrap(), Bailout::Euclidean)
+}
+
+/// Iterate the Mandelbrot sequence with a custom escape radius and norm
+pub fn mandelbrot_with<T>(c: Complex<T>, radius: T, bailout: Bailout) -> ComplexSequence<T>
 where
     T: Float,
 {
     let start: T = T::from(0.0).unwrap();
-    let radius: T = T::from(2.0).unwrap();
     ComplexSequence {
         z: Complex {
             re: start,
@@ -63,6 +127,7 @@ where
         },
         c,
         r: radius,
+        bailout,
     }
 }
 
@@ -87,6 +152,41 @@ mod tests {
         let s = mandelbrot(c);
         let res: Vec<_> = s.take(20).collect();
         assert_eq!(res, vec![c, Complex { re: 2.0, im: 0.0 }]);
+
+        let mut s = mandelbrot(c);
+        let _: Vec<_> = s.by_ref().take(20).collect();
+        assert_eq!(s.value(), Complex { re: 5.0, im: 0.0 });
+    }
+
+    #[test]
+    fn bailout_norms() {
+        let z = Complex { re: 1.5, im: -1.5 };
+        assert!(Bailout::Euclidean.escaped(z, 2.0));
+        assert!(!Bailout::Maximum.escaped(z, 2.0));
+        assert!(Bailout::Manhattan.escaped(z, 2.0));
+        assert!(!Bailout::Real.escaped(z, 2.0));
+        assert!(Bailout::Imaginary.escaped(z, 1.0));
+
+        assert!(Bailout::Real.confines_set(2.0));
+        assert!(!Bailout::Euclidean.confines_set(1.5));
+        assert!(!Bailout::Manhattan.confines_set(4.0));
+    }
+
+    #[test]
+    fn mandelbrot_radius() {
+        let c = Complex { re: 1.0, im: 0.0 };
+        let res: Vec<_> = mandelbrot_with(c, 10.0, Bailout::Euclidean)
+            .take(20)
+            .collect();
+        assert_eq!(res.len(), 3);
+
+        let c = Complex { re: 0.0, im: 1.5 };
+        let res: Vec<_> = mandelbrot_with(c, 1.0, Bailout::Real).take(20).collect();
+        assert_eq!(res.len(), 1);
+        let res: Vec<_> = mandelbrot_with(c, 1.0, Bailout::Euclidean)
+            .take(20)
+            .collect();
+        assert_eq!(res.len(), 0);
     }
 
     #[test]
@@ -0,0 +1,211 @@
+use num_complex::Complex;
+
+/// Rotation angles in degrees within the six planes of the 4D space spanned
+/// by (Re z, Im z, Re c, Im c)
+///
+/// A positive angle rotates the second axis of a plane onto the first one,
+/// e.g., `zi_cr: 90` replaces Im z with Re c for the "Zr–Cr" view.  The
+/// rotations are applied in the order of the fields, and the histogram is
+/// filled with the first two coordinates of the rotated point.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Projection {
+    #[serde(default)]
+    pub zr_zi: f64,
+    #[serde(default)]
+    pub zr_cr: f64,
+    #[serde(default)]
+    pub zr_ci: f64,
+    #[serde(default)]
+    pub zi_cr: f64,
+    #[serde(default)]
+    pub zi_ci: f64,
+    #[serde(default)]
+    pub cr_ci: f64,
+}
+
+/// The first two rows of a 4D rotation matrix
+pub type Rows = [[f64; 4]; 2];
+
+impl Projection {
+    /// Test if the projection leaves the z plane unchanged
+    pub fn is_identity(&self) -> bool {
+        *self == Projection::default()
+    }
+
+    /// The rows of the combined rotation matrix yielding the projected
+    /// coordinates
+    pub fn rows(&self) -> Rows {
+        let planes = [
+            (0, 1, self.zr_zi),
+            (0, 2, self.zr_cr),
+            (0, 3, self.zr_ci),
+            (1, 2, self.zi_cr),
+            (1, 3, self.zi_ci),
+            (2, 3, self.cr_ci),
+        ];
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        for &(i, j, angle) in planes.iter() {
+            let (sin, cos) = angle.to_radians().sin_cos();
+            let (a, b) = (m[i], m[j]);
+            for k in 0..4 {
+                m[i][k] = cos * a[k] + sin * b[k];
+                m[j][k] = cos * b[k] - sin * a[k];
+            }
+        }
+        [m[0], m[1]]
+    }
+}
+
+/// Project the point (z, c) onto the plane given by the matrix rows
+pub fn project(rows: &Rows, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+    let v = [z.re, z.im, c.re, c.im];
+    let dot = |row: &[f64; 4]| row.iter().zip(v.iter()).map(|(a, b)| a * b).sum();
+    Complex {
+        re: dot(&rows[0]),
+        im: dot(&rows[1]),
+    }
+}
+
+/// A transformation of projected orbit points into the coordinates the
+/// histogram's area is given in
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mapping {
+    /// Use the projected points as they are
+    #[default]
+    Identity,
+    /// Polar coordinates (|w|, arg w)
+    Polar,
+    /// Logarithmic polar coordinates (ln |w|, arg w), i.e., the exponential
+    /// map unrolling circles around the origin into strips
+    LogPolar,
+    /// Stereographic projection onto the Riemann sphere, rendered
+    /// orthographically after tilting the sphere by `tilt` degrees around the
+    /// real axis
+    ///
+    /// Without tilt, the viewer faces the south pole, i.e., the origin, and
+    /// the hemisphere facing away is hidden.
+    Sphere {
+        #[serde(default)]
+        tilt: f64,
+    },
+}
+
+impl Mapping {
+    /// Map a projected point, if it has an image
+    pub fn apply(self, w: Complex<f64>) -> Option<Complex<f64>> {
+        match self {
+            Mapping::Identity => Some(w),
+            Mapping::Polar => Some(Complex {
+                re: w.norm(),
+                im: w.arg(),
+            }),
+            Mapping::LogPolar => Some(Complex {
+                re: w.norm().ln(),
+                im: w.arg(),
+            }),
+            Mapping::Sphere { tilt } => {
+                let n = w.norm_sqr();
+                let x = 2.0 * w.re / (1.0 + n);
+                let y = 2.0 * w.im / (1.0 + n);
+                let z = (n - 1.0) / (n + 1.0);
+                let (sin, cos) = tilt.to_radians().sin_cos();
+                if z * cos - y * sin > 0.0 {
+                    return None;
+                }
+                Some(Complex {
+                    re: x,
+                    im: y * cos + z * sin,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn close(a: Complex<f64>, b: Complex<f64>) -> bool {
+        (a - b).norm() < 1e-12
+    }
+
+    #[test]
+    fn identity() {
+        let p = Projection::default();
+        assert!(p.is_identity());
+        let z = Complex { re: 1.0, im: 2.0 };
+        let c = Complex { re: 3.0, im: 4.0 };
+        assert_eq!(project(&p.rows(), z, c), z);
+    }
+
+    #[test]
+    fn mappings() {
+        let w = Complex { re: 0.0, im: 2.0 };
+        assert_eq!(Mapping::Identity.apply(w), Some(w));
+        let p = Mapping::Polar.apply(w).unwrap();
+        assert!(close(
+            p,
+            Complex {
+                re: 2.0,
+                im: FRAC_PI_2
+            }
+        ));
+        let p = Mapping::LogPolar.apply(w).unwrap();
+        assert!(close(
+            p,
+            Complex {
+                re: 2f64.ln(),
+                im: FRAC_PI_2
+            }
+        ));
+
+        let sphere = Mapping::Sphere { tilt: 0.0 };
+        let origin = Complex { re: 0.0, im: 0.0 };
+        assert!(close(sphere.apply(origin).unwrap(), origin));
+        let p = sphere.apply(Complex { re: 1.0, im: 0.0 }).unwrap();
+        assert!(close(p, Complex { re: 1.0, im: 0.0 }));
+        assert_eq!(sphere.apply(w), None);
+
+        let sphere = Mapping::Sphere { tilt: 90.0 };
+        let p = sphere.apply(w).unwrap();
+        assert!(close(p, Complex { re: 0.0, im: 0.6 }));
+        assert_eq!(sphere.apply(-w), None);
+    }
+
+    #[test]
+    fn views() {
+        let z = Complex { re: 1.0, im: 2.0 };
+        let c = Complex { re: 3.0, im: 4.0 };
+        let p = Projection {
+            zi_cr: 90.0,
+            ..Default::default()
+        };
+        assert!(!p.is_identity());
+        assert!(close(
+            project(&p.rows(), z, c),
+            Complex { re: 1.0, im: 3.0 }
+        ));
+
+        let p = Projection {
+            zr_zi: 90.0,
+            zi_ci: 90.0,
+            ..Default::default()
+        };
+        assert!(close(
+            project(&p.rows(), z, c),
+            Complex { re: 2.0, im: 4.0 }
+        ));
+
+        let p = Projection {
+            zr_cr: 90.0,
+            zi_ci: 90.0,
+            ..Default::default()
+        };
+        assert!(close(project(&p.rows(), z, c), c));
+    }
+}
@@ -0,0 +1,261 @@
+use num_complex::Complex;
+use serde::Deserializer;
+use std::cmp;
+use std::f64::consts::PI;
+use std::ops::Range;
+
+use cache::untagged;
+
+/// A position within an orbit, either as an absolute iterate index or as a
+/// fraction of the orbit length
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum OrbitBound {
+    Absolute(usize),
+    Fraction(f64),
+}
+
+/// Untagged form of `OrbitBound` to allow plain numbers in configurations
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PlainOrbitBound {
+    Absolute(usize),
+    Fraction(f64),
+}
+
+impl From<PlainOrbitBound> for OrbitBound {
+    fn from(bound: PlainOrbitBound) -> OrbitBound {
+        match bound {
+            PlainOrbitBound::Absolute(n) => OrbitBound::Absolute(n),
+            PlainOrbitBound::Fraction(f) => OrbitBound::Fraction(f),
+        }
+    }
+}
+
+/// Deserialize an optional orbit bound written as plain number, see
+/// [`untagged`]
+///
+/// Integers are taken as absolute iterate indices, floating point numbers
+/// as fraction of the orbit length.
+pub fn plain_orbit_bound<'de, D>(deserializer: D) -> Result<Option<OrbitBound>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    untagged(deserializer, |b: Option<PlainOrbitBound>| {
+        b.map(OrbitBound::from)
+    })
+}
+
+impl OrbitBound {
+    /// The iterate index corresponding to this bound for an orbit of length
+    /// `len`, clamped to the orbit
+    pub fn resolve(self, len: usize) -> usize {
+        match self {
+            OrbitBound::Absolute(n) => cmp::min(n, len),
+            OrbitBound::Fraction(f) => (f.clamp(0.0, 1.0) * len as f64) as usize,
+        }
+    }
+}
+
+/// The iterate indices between optional start and end bounds for an orbit
+/// of length `len`
+pub fn orbit_range(len: usize, start: Option<OrbitBound>, end: Option<OrbitBound>) -> Range<usize> {
+    let last = end.map_or(len, |b| b.resolve(len));
+    let first = start.map_or(0, |b| cmp::min(b.resolve(len), last));
+    first..last
+}
+
+/// A point an orbit has to pass by within a given radius
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Proximity {
+    pub point: [f64; 2],
+    pub radius: f64,
+}
+
+/// Additional criteria an orbit has to fulfill to be recorded in a layer
+///
+/// All criteria are optional, and an orbit has to satisfy every criterion
+/// given.  Angles are in radians within (-π, π]; a range with a lower bound
+/// larger than the upper one wraps around ±π.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Selection {
+    /// Range of the argument of the first `z` outside of the escape radius
+    #[serde(default)]
+    pub escape_angle: Option<[f64; 2]>,
+    /// Quadrants (numbered 1 to 4 counter-clockwise) the orbit may escape into
+    #[serde(default)]
+    pub escape_quadrants: Option<Vec<u8>>,
+    /// Range the maximum modulus of the orbit has to fall into
+    #[serde(default)]
+    pub max_modulus: Option<[f64; 2]>,
+    /// A point the orbit has to come close to
+    #[serde(default)]
+    pub passes_near: Option<Proximity>,
+}
+
+/// The quadrant of a complex number, counting counter-clockwise from 1
+fn quadrant(z: Complex<f64>) -> u8 {
+    match (z.re >= 0.0, z.im >= 0.0) {
+        (true, true) => 1,
+        (false, true) => 2,
+        (false, false) => 3,
+        (true, false) => 4,
+    }
+}
+
+fn angle_within(angle: f64, range: [f64; 2]) -> bool {
+    let [lower, upper] = range;
+    if lower <= upper {
+        lower <= angle && angle <= upper
+    } else {
+        angle >= lower || angle <= upper
+    }
+}
+
+impl Selection {
+    /// Test if an orbit fulfills all criteria
+    ///
+    /// The `orbit` holds all iterates within the escape radius, `escape` is
+    /// the first iterate outside of it.
+    pub fn matches(&self, orbit: &[Complex<f64>], escape: Complex<f64>) -> bool {
+        if let Some(range) = self.escape_angle {
+            let mut angle = escape.arg();
+            if angle <= -PI {
+                angle = PI;
+            }
+            if !angle_within(angle, range) {
+                return false;
+            }
+        }
+        if let Some(ref quadrants) = self.escape_quadrants {
+            if !quadrants.contains(&quadrant(escape)) {
+                return false;
+            }
+        }
+        if let Some([lower, upper]) = self.max_modulus {
+            let m = orbit.iter().map(|z| z.norm()).fold(0.0, f64::max);
+            if m < lower || m > upper {
+                return false;
+            }
+        }
+        if let Some(ref near) = self.passes_near {
+            let point = Complex {
+                re: near.point[0],
+                im: near.point[1],
+            };
+            let r2 = near.radius * near.radius;
+            if !orbit.iter().any(|z| (z - point).norm_sqr() <= r2) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orbit_bounds() {
+        assert_eq!(OrbitBound::Absolute(5).resolve(10), 5);
+        assert_eq!(OrbitBound::Absolute(50).resolve(10), 10);
+        assert_eq!(OrbitBound::Fraction(0.9).resolve(10), 9);
+        assert_eq!(OrbitBound::Fraction(2.0).resolve(10), 10);
+
+        assert_eq!(orbit_range(10, Some(OrbitBound::Absolute(4)), None), 4..10);
+
+        assert_eq!(orbit_range(10, None, None), 0..10);
+        assert_eq!(
+            orbit_range(10, Some(OrbitBound::Fraction(0.9)), None),
+            9..10
+        );
+        assert_eq!(
+            orbit_range(
+                10,
+                Some(OrbitBound::Absolute(3)),
+                Some(OrbitBound::Fraction(0.5))
+            ),
+            3..5
+        );
+        assert!(orbit_range(
+            10,
+            Some(OrbitBound::Absolute(8)),
+            Some(OrbitBound::Absolute(2))
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn orbit_bound_formats() {
+        #[derive(Debug, Deserialize, Serialize, PartialEq)]
+        struct Bounds {
+            #[serde(deserialize_with = "plain_orbit_bound")]
+            start: Option<OrbitBound>,
+            #[serde(deserialize_with = "plain_orbit_bound")]
+            end: Option<OrbitBound>,
+        }
+        let bounds = Bounds {
+            start: Some(OrbitBound::Absolute(3)),
+            end: Some(OrbitBound::Fraction(0.5)),
+        };
+        let parsed: Bounds = serde_yaml::from_str("start: 3\nend: 0.5").unwrap();
+        assert_eq!(parsed, bounds);
+        let encoded = bincode::serialize(&bounds).unwrap();
+        let decoded: Bounds = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, bounds);
+    }
+
+    #[test]
+    fn quadrants() {
+        assert_eq!(quadrant(Complex { re: 1.0, im: 1.0 }), 1);
+        assert_eq!(quadrant(Complex { re: -1.0, im: 1.0 }), 2);
+        assert_eq!(quadrant(Complex { re: -1.0, im: -1.0 }), 3);
+        assert_eq!(quadrant(Complex { re: 1.0, im: -1.0 }), 4);
+    }
+
+    #[test]
+    fn empty_selection() {
+        let s = Selection::default();
+        assert!(s.matches(&[], Complex { re: 3.0, im: 0.0 }));
+    }
+
+    #[test]
+    fn escape_selection() {
+        let escape = Complex { re: -3.0, im: 0.1 };
+        let s = Selection {
+            escape_angle: Some([3.0, -3.0]),
+            ..Default::default()
+        };
+        assert!(s.matches(&[], escape));
+        assert!(!s.matches(&[], Complex { re: 3.0, im: 0.1 }));
+
+        let s = Selection {
+            escape_quadrants: Some(vec![1, 4]),
+            ..Default::default()
+        };
+        assert!(!s.matches(&[], escape));
+        assert!(s.matches(&[], Complex { re: 3.0, im: -0.1 }));
+    }
+
+    #[test]
+    fn orbit_selection() {
+        let orbit = [Complex { re: 0.5, im: 0.0 }, Complex { re: 1.0, im: 1.0 }];
+        let escape = Complex { re: 3.0, im: 0.0 };
+        let s = Selection {
+            max_modulus: Some([1.0, 1.5]),
+            ..Default::default()
+        };
+        assert!(s.matches(&orbit, escape));
+        assert!(!s.matches(&orbit[..1], escape));
+
+        let s = Selection {
+            passes_near: Some(Proximity {
+                point: [0.6, 0.0],
+                radius: 0.2,
+            }),
+            ..Default::default()
+        };
+        assert!(s.matches(&orbit, escape));
+        assert!(!s.matches(&orbit[1..], escape));
+    }
+}
@@ -0,0 +1,113 @@
+//! Exact statistics of histogram counts
+use std::collections::HashMap;
+
+/// How often each count occurs among the pixels of a histogram
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frequencies {
+    /// Distinct counts in ascending order, with the number of pixels
+    /// holding them
+    table: Vec<(u32, u64)>,
+    pixels: u64,
+}
+
+impl Frequencies {
+    pub fn new<I: IntoIterator<Item = u32>>(counts: I) -> Frequencies {
+        let mut table = HashMap::new();
+        for c in counts {
+            *table.entry(c).or_insert(0) += 1;
+        }
+        Frequencies::from(table)
+    }
+
+    /// The distinct counts in ascending order, with their frequencies
+    pub fn table(&self) -> &[(u32, u64)] {
+        &self.table
+    }
+
+    /// The number of pixels
+    pub fn pixels(&self) -> u64 {
+        self.pixels
+    }
+
+    /// The sum of all counts
+    pub fn total(&self) -> u64 {
+        self.table.iter().map(|&(c, n)| c as u64 * n).sum()
+    }
+
+    /// The largest count
+    pub fn max(&self) -> u32 {
+        self.table.last().map(|&(c, _)| c).unwrap_or(0)
+    }
+
+    /// The fraction of pixels with a count of zero
+    pub fn empty(&self) -> f64 {
+        match self.table.first() {
+            Some(&(0, n)) => n as f64 / self.pixels as f64,
+            _ => 0.0,
+        }
+    }
+
+    /// The smallest count that at least `p` percent of the non-empty pixels
+    /// do not exceed
+    ///
+    /// Empty pixels are left out, as they often make up most of an image,
+    /// which would pull high percentiles down to zero.
+    pub fn percentile(&self, p: f64) -> u32 {
+        let filled = self.table.iter().filter(|&&(c, _)| c > 0);
+        let pixels: u64 = filled.clone().map(|&(_, n)| n).sum();
+        let rank = ((p / 100.0).clamp(0.0, 1.0) * pixels as f64).ceil() as u64;
+        let mut seen = 0;
+        for &(c, n) in filled {
+            seen += n;
+            if seen >= rank {
+                return c;
+            }
+        }
+        self.max()
+    }
+}
+
+impl From<HashMap<u32, u64>> for Frequencies {
+    /// Tabulate the number of pixels per count
+    fn from(map: HashMap<u32, u64>) -> Frequencies {
+        let mut table: Vec<_> = map.into_iter().collect();
+        table.sort_unstable();
+        Frequencies {
+            pixels: table.iter().map(|&(_, n)| n).sum(),
+            table,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let f = Frequencies::new(vec![0, 3, 0, 1, 7, 3, 0, 0, 2, 3]);
+        assert_eq!(f.table(), &[(0, 4), (1, 1), (2, 1), (3, 3), (7, 1)]);
+        assert_eq!(f.pixels(), 10);
+        assert_eq!(f.total(), 19);
+        assert_eq!(f.max(), 7);
+        assert_eq!(f.empty(), 0.4);
+        assert_eq!(f.percentile(0.0), 1);
+        assert_eq!(f.percentile(16.0), 1);
+        assert_eq!(f.percentile(17.0), 2);
+        assert_eq!(f.percentile(50.0), 3);
+        assert_eq!(f.percentile(83.0), 3);
+        assert_eq!(f.percentile(84.0), 7);
+        assert_eq!(f.percentile(100.0), 7);
+
+        // A sparse layer, where percentiles of all pixels would be zero
+        let f = Frequencies::new((0..10000).map(|i| if i % 100 == 0 { i / 100 + 1 } else { 0 }));
+        assert_eq!(f.empty(), 0.99);
+        assert_eq!(f.percentile(50.0), 50);
+        assert_eq!(f.percentile(99.9), 100);
+
+        let f = Frequencies::new(vec![]);
+        assert_eq!((f.max(), f.empty(), f.percentile(50.0)), (0, 0.0, 0));
+        let f = Frequencies::new(vec![0; 10]);
+        assert_eq!((f.max(), f.empty(), f.percentile(50.0)), (0, 1.0, 0));
+    }
+}
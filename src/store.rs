@@ -0,0 +1,194 @@
+//! A directory of caches, named after the parameters they were computed with
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use cache::{Cache, Configuration};
+use format;
+
+/// A cache within a store
+#[derive(Debug)]
+pub struct Entry {
+    pub id: String,
+    /// The size of the cache and its backup in bytes
+    pub size: u64,
+    /// The last time the cache was used
+    pub used: SystemTime,
+    /// The configuration file the cache was last used with
+    pub source: Option<String>,
+}
+
+/// A directory holding caches keyed by [`Configuration::cache_id`]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    /// Open the store in `dir`, creating the directory if needed
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Store, Box<dyn Error>> {
+        fs::create_dir_all(&dir)?;
+        Ok(Store {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn file(&self, id: &str, extension: &str) -> String {
+        let path = self.dir.join(format!("{}.{}", id, extension));
+        path.to_string_lossy().into_owned()
+    }
+
+    /// The cache file for `config`
+    pub fn path(&self, config: &Configuration) -> String {
+        self.file(&config.cache_id(), "cache")
+    }
+
+    /// Load the cache for `config`, used with the configuration file
+    /// `source`
+    ///
+    /// Without a complete cache, layers are taken over from caches in the
+    /// store that share all other parameters.
+    pub fn load(&self, config: &Configuration, source: &str) -> Result<Cache, Box<dyn Error>> {
+        let id = config.cache_id();
+        let filename = self.file(&id, "cache");
+        let mut cache = Cache::load(&filename, config);
+        if !cache.valid {
+            for entry in self.entries()?.iter().filter(|e| e.id != id) {
+                match cache.seed(&self.file(&entry.id, "cache")) {
+                    Ok(0) | Err(format::CacheError::Configuration) => {}
+                    Ok(n) => info!("re-using {} layer(s) of cache {}", n, entry.id),
+                    Err(e) => warn!("not using cache {}: {}", entry.id, e),
+                }
+                if cache.valid {
+                    break;
+                }
+            }
+        }
+        fs::write(self.file(&id, "source"), source)?;
+        if let Ok(f) = File::options().write(true).open(&filename) {
+            f.set_modified(SystemTime::now())?;
+        }
+        Ok(cache)
+    }
+
+    /// All caches in the store, most recently used first
+    pub fn entries(&self) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let mut entries = vec![];
+        for item in fs::read_dir(&self.dir)? {
+            let path = item?.path();
+            let id = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(id) if path.extension().is_some_and(|e| e == "cache") => id.to_string(),
+                _ => continue,
+            };
+            let metadata = fs::metadata(&path)?;
+            let backup = fs::metadata(format::backup(&self.file(&id, "cache")));
+            entries.push(Entry {
+                size: metadata.len() + backup.map(|m| m.len()).unwrap_or(0),
+                used: metadata.modified()?,
+                source: fs::read_to_string(self.file(&id, "source")).ok(),
+                id,
+            });
+        }
+        entries.sort_by_key(|e| std::cmp::Reverse(e.used));
+        Ok(entries)
+    }
+
+    /// Remove a cache together with its backup and source
+    pub fn remove(&self, id: &str) -> io::Result<()> {
+        let filename = self.file(id, "cache");
+        for f in &[format::backup(&filename), self.file(id, "source")] {
+            if let Err(e) = fs::remove_file(f) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+        fs::remove_file(filename)
+    }
+
+    /// Remove caches not used within `max_age`, then the least recently used
+    /// ones until the store holds at most `max_size` bytes, returning the
+    /// removed caches
+    pub fn gc(
+        &self,
+        max_age: Option<Duration>,
+        max_size: Option<u64>,
+    ) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let now = SystemTime::now();
+        let mut total = 0;
+        let mut removed = vec![];
+        for entry in self.entries()? {
+            let age = now.duration_since(entry.used).unwrap_or_default();
+            if max_age.is_some_and(|m| age >= m) || max_size.is_some_and(|m| total + entry.size > m)
+            {
+                self.remove(&entry.id)?;
+                removed.push(entry);
+            } else {
+                total += entry.size;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn config(iterations: &[usize]) -> Configuration {
+        let layers: Vec<_> = iterations
+            .iter()
+            .map(|i| format!("  - iterations: {}\n    color: [1, 2, 3]\n", i))
+            .collect();
+        let yaml = format!(
+            "dimensions: {{x: 8, y: 4}}\narea: {{x: [-2, 2], y: [-1, 1]}}\n\
+             colorization: {{exponent: 1.0}}\nlayers:\n{}",
+            layers.concat()
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn cache_ids() {
+        let a = config(&[10, 20]);
+        assert_eq!(a.cache_id(), config(&[10, 20]).cache_id());
+        assert_ne!(a.cache_id(), config(&[10, 21]).cache_id());
+        let mut b = config(&[10, 20]);
+        b.layers[0].color = [0, 0, 0];
+        assert_eq!(a.cache_id(), b.cache_id());
+        b.escape_radius = 3.0;
+        assert_ne!(a.cache_id(), b.cache_id());
+    }
+
+    #[test]
+    fn seed_and_collect() {
+        let dir = tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let first = config(&[10, 20]);
+        let mut cache = store.load(&first, "first.yaml").unwrap();
+        assert!(!cache.valid);
+        cache.populate();
+        cache.layers[1].data[0] = 12345;
+        cache.dump(&store.path(&first)).unwrap();
+
+        let second = config(&[20, 30]);
+        let cache = store.load(&second, "second.yaml").unwrap();
+        assert!(!cache.valid);
+        assert_eq!(cache.layers[0].data[0], 12345);
+        cache.dump(&store.path(&second)).unwrap();
+
+        let entries = store.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].source, Some(String::from("second.yaml")));
+        assert!(store.gc(None, None).unwrap().is_empty());
+        let removed = store.gc(None, Some(entries[0].size)).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].id, first.cache_id());
+        assert_eq!(fs::read_dir(dir.path().join("store")).unwrap().count(), 2);
+        store.gc(Some(Duration::from_secs(0)), None).unwrap();
+        assert!(store.entries().unwrap().is_empty());
+    }
+}
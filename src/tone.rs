@@ -0,0 +1,361 @@
+//! Tone curves mapping histogram counts onto intensities
+use rayon::prelude::*;
+use std::fmt;
+
+use stats::Frequencies;
+
+/// A monotonic curve mapping a count onto an intensity in [0, 1]
+///
+/// Counts are given relative to the noise floor, i.e., `x` and `max` are
+/// the count of a pixel and the largest count of a layer less the floor.
+/// Implementations map 0 to 0 and `max` to 1.
+pub trait ToneMap {
+    fn map(&self, x: f32, max: f32) -> f32;
+}
+
+/// The available tone curves, as named in configurations
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    Linear,
+    #[default]
+    Log,
+    Power,
+    Asinh,
+    Sigmoid,
+    Filmic,
+    Equalize,
+}
+
+/// A count of a layer, given directly or as percentile of the counts of the
+/// pixels with a count above zero
+///
+/// Empty pixels are not ranked, so that a percentile of a sparse layer does
+/// not resolve to zero.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Count(u32),
+    Percentile(f64),
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Count(c) => write!(f, "count {}", c),
+            Level::Percentile(p) => write!(f, "percentile {}", p),
+        }
+    }
+}
+
+impl Level {
+    /// The count of the level, where only percentiles take the
+    /// `frequencies` of the counts
+    pub fn resolve<'a, F>(self, frequencies: F) -> u32
+    where
+        F: FnOnce() -> &'a Frequencies,
+    {
+        match self {
+            Level::Count(c) => c,
+            Level::Percentile(p) => frequencies().percentile(p),
+        }
+    }
+}
+
+/// Raise `v` to the power `exponent`, avoiding `powf` for common exponents
+fn gamma(v: f32, exponent: f32) -> f32 {
+    if (exponent - 1.0).abs() < 1e-6 {
+        v
+    } else if (exponent - 0.5).abs() < 1e-6 {
+        v.sqrt()
+    } else {
+        v.powf(exponent)
+    }
+}
+
+/// The logarithm of `x` relative to the one of `max`, treating counts below
+/// one as one
+fn log_scale(x: f32, max: f32) -> f32 {
+    x.max(1.0).log2() / max.max(2.0).log2()
+}
+
+/// Counts proportional to intensities
+pub struct Linear;
+
+impl ToneMap for Linear {
+    fn map(&self, x: f32, max: f32) -> f32 {
+        x.max(0.0) / max.max(1.0)
+    }
+}
+
+/// The logarithm of counts, raised to `exponent`
+pub struct Log {
+    pub exponent: f32,
+}
+
+impl ToneMap for Log {
+    fn map(&self, x: f32, max: f32) -> f32 {
+        gamma(log_scale(x, max), self.exponent)
+    }
+}
+
+/// Counts raised to `exponent`
+pub struct Power {
+    pub exponent: f32,
+}
+
+impl ToneMap for Power {
+    fn map(&self, x: f32, max: f32) -> f32 {
+        gamma(Linear.map(x, max), self.exponent)
+    }
+}
+
+/// The inverse hyperbolic sine of counts, linear for counts well below
+/// `softening` and logarithmic above
+pub struct Asinh {
+    pub softening: f32,
+}
+
+impl ToneMap for Asinh {
+    fn map(&self, x: f32, max: f32) -> f32 {
+        let s = self.softening.max(f32::MIN_POSITIVE);
+        (x.max(0.0) / s).asinh() / (max.max(1.0) / s).asinh()
+    }
+}
+
+/// A logistic curve over the logarithm of counts, centered on `midpoint`
+/// within [0, 1] and with a slope given by `contrast`
+pub struct Sigmoid {
+    pub midpoint: f32,
+    pub contrast: f32,
+}
+
+impl ToneMap for Sigmoid {
+    fn map(&self, x: f32, max: f32) -> f32 {
+        let logistic = |v: f32| 1.0 / (1.0 + (-self.contrast * (v - self.midpoint)).exp());
+        let (low, high) = (logistic(0.0), logistic(1.0));
+        if high <= low {
+            return log_scale(x, max);
+        }
+        (logistic(log_scale(x, max)) - low) / (high - low)
+    }
+}
+
+/// The filmic curve of John Hable, with the largest count mapped to
+/// `exposure` before compression
+pub struct Filmic {
+    pub exposure: f32,
+}
+
+impl ToneMap for Filmic {
+    fn map(&self, x: f32, max: f32) -> f32 {
+        let curve = |v: f32| {
+            let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+            (v * (a * v + c * b) + d * e) / (v * (a * v + b) + d * f) - e / f
+        };
+        let exposure = self.exposure.max(f32::MIN_POSITIVE);
+        curve(exposure * Linear.map(x, max)) / curve(exposure)
+    }
+}
+
+/// Histogram equalization, mapping each count onto the fraction of the
+/// pixels above the noise floor with at most this count
+pub struct Equalize {
+    /// Distinct counts above the floor, less the floor, in ascending order
+    /// with their cumulative fraction
+    cdf: Vec<(f32, f32)>,
+}
+
+impl Equalize {
+    pub fn new(frequencies: &Frequencies, floor: f32) -> Equalize {
+        let above: Vec<_> = frequencies
+            .table()
+            .iter()
+            .filter(|&&(c, _)| c as f32 > floor)
+            .collect();
+        let total: u64 = above.iter().map(|&&(_, n)| n).sum();
+        let mut seen = 0;
+        let cdf = above
+            .iter()
+            .map(|&&(c, n)| {
+                seen += n;
+                (c as f32 - floor, seen as f32 / total as f32)
+            })
+            .collect();
+        Equalize { cdf }
+    }
+}
+
+impl ToneMap for Equalize {
+    fn map(&self, x: f32, _max: f32) -> f32 {
+        match self.cdf.partition_point(|&(c, _)| c <= x) {
+            0 => 0.0,
+            n => self.cdf[n - 1].1,
+        }
+    }
+}
+
+/// The number of logarithmic intensity levels of contrast-limited
+/// equalization
+const LEVELS: usize = 256;
+
+/// Contrast-limited adaptive histogram equalization (CLAHE)
+///
+/// The image is split into tiles, each equalized separately over
+/// logarithmic intensity levels, with the frequency of any level limited to
+/// `clip_limit` times the average and the excess spread evenly over all
+/// levels.  Pixels are mapped by interpolating between the four closest
+/// tile centers.
+pub struct Clahe {
+    width: f32,
+    height: f32,
+    tiles: usize,
+    scale: Log,
+    max: f32,
+    /// The mapping of intensity levels per tile, in row-major order
+    maps: Vec<[f32; LEVELS]>,
+}
+
+impl Clahe {
+    /// Equalize the counts of an image of `width` by `height` pixels, given
+    /// relative to the noise floor by `count(x, y)` with a maximum of `max`
+    pub fn new<F>(width: u16, height: u16, tiles: u16, clip_limit: f32, max: f32, count: F) -> Clahe
+    where
+        F: Fn(u16, u16) -> f32 + Sync,
+    {
+        let tiles = tiles.clamp(1, width.min(height).max(1)) as usize;
+        let scale = Log { exponent: 1.0 };
+        let bounds = |n: u16, t: usize| {
+            let n = n as usize;
+            (t * n / tiles) as u16..((t + 1) * n / tiles) as u16
+        };
+        let maps = (0..tiles * tiles)
+            .into_par_iter()
+            .map(|t| {
+                let mut hist = [0f32; LEVELS];
+                for y in bounds(height, t / tiles) {
+                    for x in bounds(width, t % tiles) {
+                        let c = count(x, y);
+                        if c > 0.0 {
+                            hist[level(&scale, c, max)] += 1.0;
+                        }
+                    }
+                }
+                let total: f32 = hist.iter().sum();
+                let limit = (clip_limit * total / LEVELS as f32).max(1.0);
+                let excess: f32 = hist.iter().map(|&h| (h - limit).max(0.0)).sum();
+                let mut map = [0f32; LEVELS];
+                let mut seen = 0.0;
+                for (m, h) in map.iter_mut().zip(hist.iter()) {
+                    seen += h.min(limit) + excess / LEVELS as f32;
+                    *m = if total > 0.0 { seen / total } else { 0.0 };
+                }
+                map
+            })
+            .collect();
+        Clahe {
+            width: width as f32,
+            height: height as f32,
+            tiles,
+            scale,
+            max,
+            maps,
+        }
+    }
+
+    /// The intensity of the pixel at `x`, `y` with the count `c` relative to
+    /// the noise floor
+    pub fn map(&self, x: u16, y: u16, c: f32) -> f32 {
+        if c <= 0.0 {
+            return 0.0;
+        }
+        let level = level(&self.scale, c, self.max);
+        let last = (self.tiles - 1) as f32;
+        let position = |p: u16, n: f32| {
+            let t = ((p as f32 + 0.5) * self.tiles as f32 / n - 0.5).clamp(0.0, last);
+            let lower = t.floor();
+            (lower as usize, (lower + 1.0).min(last) as usize, t - lower)
+        };
+        let (x0, x1, wx) = position(x, self.width);
+        let (y0, y1, wy) = position(y, self.height);
+        let value = |tx: usize, ty: usize| self.maps[tx + ty * self.tiles][level];
+        let top = value(x0, y0) * (1.0 - wx) + value(x1, y0) * wx;
+        let bottom = value(x0, y1) * (1.0 - wx) + value(x1, y1) * wx;
+        top * (1.0 - wy) + bottom * wy
+    }
+}
+
+/// The logarithmic intensity level of the count `c`
+fn level(scale: &Log, c: f32, max: f32) -> usize {
+    let v = scale.map(c, max).clamp(0.0, 1.0);
+    (v * (LEVELS - 1) as f32).round() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves() {
+        let curves: Vec<Box<dyn ToneMap>> = vec![
+            Box::new(Linear),
+            Box::new(Log { exponent: 0.25 }),
+            Box::new(Power { exponent: 0.5 }),
+            Box::new(Asinh { softening: 10.0 }),
+            Box::new(Sigmoid {
+                midpoint: 0.5,
+                contrast: 8.0,
+            }),
+            Box::new(Filmic { exposure: 4.0 }),
+        ];
+        for curve in curves.iter() {
+            let values: Vec<_> = (-2..=1000).map(|x| curve.map(x as f32, 1000.0)).collect();
+            assert!(values[0].abs() < 1e-6);
+            assert!((values[values.len() - 1] - 1.0).abs() < 1e-5);
+            assert!(values.windows(2).all(|w| w[0] <= w[1]));
+        }
+        assert_eq!(Power { exponent: 0.5 }.map(250.0, 1000.0), 0.5);
+        assert_eq!(Log { exponent: 2.0 }.map(32.0, 1024.0), 0.25);
+    }
+
+    #[test]
+    fn equalization() {
+        let frequencies = Frequencies::new(vec![0, 0, 3, 8, 8, 20, 1000]);
+        let eq = Equalize::new(&frequencies, 5.0);
+        assert_eq!(eq.map(-5.0, 995.0), 0.0);
+        assert_eq!(eq.map(0.0, 995.0), 0.0);
+        assert_eq!(eq.map(3.0, 995.0), 0.5);
+        assert_eq!(eq.map(14.0, 995.0), 0.5);
+        assert_eq!(eq.map(15.0, 995.0), 0.75);
+        assert_eq!(eq.map(995.0, 995.0), 1.0);
+
+        // The left half is dim and the right half bright, which adaptive
+        // equalization brightens and darkens, respectively
+        let count = |x: u16, _y: u16| {
+            if x < 32 {
+                1.0 + x as f32
+            } else {
+                100.0 * x as f32
+            }
+        };
+        let clahe = Clahe::new(64, 64, 2, 100.0, 6300.0, count);
+        assert_eq!(clahe.map(5, 5, 0.0), 0.0);
+        assert!(clahe.map(0, 0, 32.0) > 0.9);
+        assert!(clahe.map(63, 63, 3200.0) < 0.1);
+        assert_eq!(clahe.map(63, 0, 6300.0), 1.0);
+        let row: Vec<_> = (0..64).map(|x| clahe.map(x, 20, 500.0)).collect();
+        assert!(row.windows(2).all(|w| w[0] >= w[1]));
+    }
+
+    #[test]
+    fn levels() {
+        let counts = (1..=1000).chain(vec![1000000]).chain(vec![0; 5000]);
+        let frequencies = Frequencies::new(counts);
+        let level: Level = serde_yaml::from_str("{percentile: 99.9}").unwrap();
+        assert_eq!(level, Level::Percentile(99.9));
+        assert_eq!(level.resolve(|| &frequencies), 1000);
+        assert_eq!(level.to_string(), "percentile 99.9");
+        let level: Level = serde_yaml::from_str("count: 20").unwrap();
+        assert_eq!(level.resolve(|| &frequencies), 20);
+    }
+}
@@ -0,0 +1,330 @@
+use num_traits::cast;
+use num_traits::Float;
+use std::iter;
+
+/// The direction the imaginary axis points to in images
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    /// The top row of an image holds the smallest imaginary parts
+    #[default]
+    Down,
+    /// The top row of an image holds the largest imaginary parts, as in
+    /// common plots of the complex plane
+    Up,
+}
+
+struct Binning<T> {
+    scale: T,
+    min: T,
+    num: u16,
+}
+
+impl<T> Binning<T>
+where
+    T: Float,
+{
+    /// The bin containing `n`, where each bin includes its lower edge but
+    /// not its upper one
+    fn bin(&self, n: T) -> Option<u16> {
+        let pos = (n - self.min) * self.scale;
+        if pos.is_nan() || pos < T::zero() {
+            return None;
+        }
+        cast(pos).filter(|&c| c < self.num)
+    }
+
+    /// The coordinate at the position `pos`, measured in bins
+    fn value(&self, pos: T) -> T {
+        self.min + pos / self.scale
+    }
+
+    fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.num).map(move |n| self.value(cast::<f32, T>(n as f32 + 0.5).unwrap()))
+    }
+}
+
+/// An oblique coordinate frame around a center point
+struct Frame<T> {
+    center: (T, T),
+    xbase: (T, T),
+    ybase: (T, T),
+}
+
+impl<T> Frame<T>
+where
+    T: Float,
+{
+    fn to_local(&self, x: T, y: T) -> (T, T) {
+        let (dx, dy) = (x - self.center.0, y - self.center.1);
+        let (a, b) = (self.xbase, self.ybase);
+        let det = a.0 * b.1 - a.1 * b.0;
+        ((dx * b.1 - dy * b.0) / det, (a.0 * dy - a.1 * dx) / det)
+    }
+
+    fn to_global(&self, u: T, v: T) -> (T, T) {
+        (
+            self.center.0 + u * self.xbase.0 + v * self.ybase.0,
+            self.center.1 + u * self.xbase.1 + v * self.ybase.1,
+        )
+    }
+}
+
+/// The mapping between a regular grid of pixels and the complex plane
+///
+/// The pixels are either aligned with the coordinate axes, or laid out along
+/// the axes of a rotated and sheared frame.  Each pixel includes its lower
+/// edges, but not its upper ones.
+///
+/// Pixel data is stored row by row, starting with the row of the smallest
+/// (local) imaginary parts.  Image coordinates, with the first row at the top,
+/// are translated according to the orientation, which defaults to the storage
+/// order.
+pub struct Viewport<T> {
+    frame: Option<Frame<T>>,
+    xaxis: Binning<T>,
+    yaxis: Binning<T>,
+    orientation: Orientation,
+}
+
+impl<T> Viewport<T>
+where
+    T: Float,
+{
+    pub fn new(xmin: T, xmax: T, xnum: u16, ymin: T, ymax: T, ynum: u16) -> Viewport<T> {
+        let xaxis = Binning {
+            scale: T::from(xnum).unwrap() / (xmax - xmin),
+            min: xmin,
+            num: xnum,
+        };
+        let yaxis = Binning {
+            scale: T::from(ynum).unwrap() / (ymax - ymin),
+            min: ymin,
+            num: ynum,
+        };
+        Viewport {
+            frame: None,
+            xaxis,
+            yaxis,
+            orientation: Orientation::Down,
+        }
+    }
+
+    /// Create a viewport of `width` by `height` around `center`, with its x
+    /// axis rotated counter-clockwise by `rotation` and its y axis rotated by
+    /// an additional `shear`, both in radians
+    pub fn rotated(
+        center: (T, T),
+        width: T,
+        height: T,
+        rotation: T,
+        shear: T,
+        xnum: u16,
+        ynum: u16,
+    ) -> Viewport<T> {
+        let half = T::from(0.5).unwrap();
+        let mut viewport = Viewport::new(
+            -width * half,
+            width * half,
+            xnum,
+            -height * half,
+            height * half,
+            ynum,
+        );
+        let skew = rotation + shear;
+        viewport.frame = Some(Frame {
+            center,
+            xbase: (rotation.cos(), rotation.sin()),
+            ybase: (-skew.sin(), skew.cos()),
+        });
+        viewport
+    }
+
+    /// Use the given orientation for image coordinates
+    pub fn oriented(mut self, orientation: Orientation) -> Viewport<T> {
+        self.orientation = orientation;
+        self
+    }
+
+    /// The number of pixels along x and y
+    pub fn dimensions(&self) -> (u16, u16) {
+        (self.xaxis.num, self.yaxis.num)
+    }
+
+    /// The number of pixels of the viewport
+    pub fn size(&self) -> usize {
+        self.xaxis.num as usize * self.yaxis.num as usize
+    }
+
+    /// The centers of all pixels in storage order
+    pub fn centers(&self) -> impl Iterator<Item = (T, T)> + '_ {
+        self.yaxis
+            .iter()
+            .flat_map(move |y| self.xaxis.iter().zip(iter::repeat(y)))
+            .map(move |(u, v)| self.to_global(u, v))
+    }
+
+    /// The storage index of the pixel containing `x`, `y`, if any
+    pub fn locate(&self, x: T, y: T) -> Option<usize> {
+        let (u, v) = self.to_local(x, y);
+        let nx = self.xaxis.bin(u)?;
+        let ny = self.yaxis.bin(v)?;
+        Some(nx as usize + ny as usize * self.xaxis.num as usize)
+    }
+
+    /// The storage index of the image pixel at `x`, `y`
+    pub fn index(&self, x: u16, y: u16) -> usize {
+        x as usize + self.row(y) as usize * self.xaxis.num as usize
+    }
+
+    /// The point of the plane at the continuous image coordinates `x`, `y`,
+    /// measured in pixels from the top left corner
+    pub fn to_plane(&self, x: T, y: T) -> (T, T) {
+        let v = match self.orientation {
+            Orientation::Down => y,
+            Orientation::Up => T::from(self.yaxis.num).unwrap() - y,
+        };
+        self.to_global(self.xaxis.value(x), self.yaxis.value(v))
+    }
+
+    /// The center of the image pixel at `x`, `y`
+    pub fn pixel_center(&self, x: u16, y: u16) -> (T, T) {
+        let half = T::from(0.5).unwrap();
+        self.to_plane(T::from(x).unwrap() + half, T::from(y).unwrap() + half)
+    }
+
+    /// The image pixel containing the point `x`, `y`, if any
+    pub fn to_pixel(&self, x: T, y: T) -> Option<(u16, u16)> {
+        let (u, v) = self.to_local(x, y);
+        let nx = self.xaxis.bin(u)?;
+        let ny = self.yaxis.bin(v)?;
+        Some((nx, self.row(ny)))
+    }
+
+    /// Translate between image rows and storage rows, in either direction
+    fn row(&self, y: u16) -> u16 {
+        match self.orientation {
+            Orientation::Down => y,
+            Orientation::Up => self.yaxis.num - 1 - y,
+        }
+    }
+
+    fn to_local(&self, x: T, y: T) -> (T, T) {
+        match self.frame {
+            Some(ref f) => f.to_local(x, y),
+            None => (x, y),
+        }
+    }
+
+    fn to_global(&self, u: T, v: T) -> (T, T) {
+        match self.frame {
+            Some(ref f) => f.to_global(u, v),
+            None => (u, v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::{FRAC_1_SQRT_2, FRAC_PI_2, FRAC_PI_4};
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() + (a.1 - b.1).abs() < 1e-12
+    }
+
+    #[test]
+    fn binning_iter() {
+        let bins = Binning {
+            scale: 2.0,
+            min: 0.0,
+            num: 2,
+        };
+        let res: Vec<f64> = bins.iter().collect();
+        assert_eq!(res, vec![0.25, 0.75]);
+    }
+
+    #[test]
+    fn binning_fill() {
+        let bins = Binning {
+            scale: 1.0,
+            min: 0.0,
+            num: 2,
+        };
+        let b1 = bins.bin(0.1);
+        assert_eq!(b1, Some(0));
+        let b2 = bins.bin(5.0);
+        assert_eq!(b2, None);
+    }
+
+    #[test]
+    fn binning_edges() {
+        let bins = Binning {
+            scale: 1.0,
+            min: 0.0,
+            num: 2,
+        };
+        assert_eq!(bins.bin(0.0), Some(0));
+        assert_eq!(bins.bin(1.0), Some(1));
+        assert_eq!(bins.bin(2.0), None);
+        assert_eq!(bins.bin(-0.5), None);
+        assert_eq!(bins.bin(f64::NAN), None);
+    }
+
+    #[test]
+    fn viewport_usage() {
+        let viewport = Viewport::new(-1.0, 1.0, 2, 0.0, 3.0, 3);
+        assert_eq!(viewport.size(), 6);
+        assert_eq!(viewport.dimensions(), (2, 3));
+        let centers: Vec<_> = viewport.centers().collect();
+        assert_eq!(centers[1], (0.5, 0.5));
+        assert_eq!(centers[5], (0.5, 2.5));
+        assert_eq!(viewport.locate(0.1, 1.2), Some(3));
+        assert_eq!(viewport.locate(f64::NAN, 1.2), None);
+        assert_eq!(viewport.locate(0.1, 3.2), None);
+    }
+
+    #[test]
+    fn viewport_orientation() {
+        let down = Viewport::new(-1.0, 1.0, 2, 0.0, 3.0, 3);
+        let up = Viewport::new(-1.0, 1.0, 2, 0.0, 3.0, 3).oriented(Orientation::Up);
+        assert_eq!(down.pixel_center(1, 0), (0.5, 0.5));
+        assert_eq!(up.pixel_center(1, 0), (0.5, 2.5));
+        assert_eq!(down.to_plane(0.0, 0.0), (-1.0, 0.0));
+        assert_eq!(up.to_plane(0.0, 0.0), (-1.0, 3.0));
+        assert_eq!(down.to_pixel(0.5, 0.5), Some((1, 0)));
+        assert_eq!(up.to_pixel(0.5, 0.5), Some((1, 2)));
+        assert_eq!(up.to_pixel(0.5, 3.0), None);
+        for viewport in [down, up].iter() {
+            let centers: Vec<_> = viewport.centers().collect();
+            for y in 0..3 {
+                for x in 0..2 {
+                    let (re, im) = viewport.pixel_center(x, y);
+                    assert_eq!(viewport.to_pixel(re, im), Some((x, y)));
+                    assert_eq!(centers[viewport.index(x, y)], (re, im));
+                    assert_eq!(viewport.locate(re, im), Some(viewport.index(x, y)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotated_viewport() {
+        let viewport = Viewport::rotated((1.0, 1.0), 2.0, 1.0, FRAC_PI_2, 0.0, 2, 1);
+        let centers: Vec<_> = viewport.centers().collect();
+        assert!(close(centers[0], (1.0, 0.5)));
+        assert!(close(centers[1], (1.0, 1.5)));
+        assert_eq!(viewport.locate(1.1, 0.6), Some(0));
+        assert_eq!(viewport.locate(0.9, 1.9), Some(1));
+        assert_eq!(viewport.locate(2.0, 1.0), None);
+        assert!(close(viewport.to_plane(0.0, 0.0), (1.5, 0.0)));
+
+        let viewport = Viewport::rotated((0.0, 0.0), 2.0, 2.0, 0.0, FRAC_PI_4, 2, 2);
+        let centers: Vec<_> = viewport.centers().collect();
+        let r = 0.5 * FRAC_1_SQRT_2;
+        assert!(close(centers[0], (-0.5 + r, -r)));
+        for (i, &(x, y)) in centers.iter().enumerate() {
+            assert_eq!(viewport.locate(x, y), Some(i));
+        }
+    }
+}